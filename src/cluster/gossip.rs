@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use super::Membership;
+use crate::discovery::ServiceRegistry;
+use crate::health::HealthCheck;
+
+/// Results of the health checks a node runs, pushed to every peer. Doubles
+/// as the heartbeat that keeps the sender in everyone's live member set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub node_id: u64,
+    pub checks: Vec<(String, HealthCheck)>,
+}
+
+pub async fn run_gossip(membership: Membership, registry: ServiceRegistry, interval: Duration) {
+    let client = reqwest::Client::new();
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let ring = membership.ring();
+        let health = registry.health();
+        let checks = match registry.list_services().await {
+            Ok(services) => services
+                .into_iter()
+                .filter(|service| membership.should_check(&ring, &service.id))
+                .filter_map(|service| health.check(&service.id).map(|check| (service.id, check)))
+                .collect(),
+            Err(e) => {
+                tracing::warn!("Failed to list services for gossip: {}", e);
+                Vec::new()
+            }
+        };
+
+        let report = HealthReport {
+            node_id: membership.node_id(),
+            checks,
+        };
+        let body = match serde_json::to_vec(&report) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::warn!("Failed to encode health report: {}", e);
+                continue;
+            }
+        };
+
+        let sends = membership.peers().iter().map(|peer| {
+            client
+                .post(format!("http://{}/cluster/health", peer.address))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .timeout(interval)
+                .send()
+        });
+
+        for (peer, result) in membership.peers().iter().zip(join_all(sends).await) {
+            if let Err(e) = result {
+                tracing::debug!("Failed to gossip health to node {}: {}", peer.id, e);
+            }
+        }
+    }
+}
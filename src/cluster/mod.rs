@@ -0,0 +1,130 @@
+mod gossip;
+mod ring;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+pub use gossip::{run_gossip, HealthReport};
+pub use ring::{hash, HashRing};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub id: u64,
+    pub address: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberStatus {
+    pub id: u64,
+    pub address: Option<String>,
+    pub alive: bool,
+}
+
+/// Cluster membership as seen by this node.
+///
+/// Peers count as live only once they have been heard from within
+/// `member_timeout`, so a node that starts alone checks everything and
+/// hands work over as peers report in.
+#[derive(Clone)]
+pub struct Membership {
+    node_id: u64,
+    peers: Arc<Vec<Member>>,
+    last_seen: Arc<DashMap<u64, Instant>>,
+    /// Which node last reported on each instance, and when.
+    reports: Arc<DashMap<String, (u64, Instant)>>,
+    member_timeout: Duration,
+}
+
+impl Membership {
+    pub fn new(node_id: u64, peers: Vec<Member>, member_timeout: Duration) -> Self {
+        Self {
+            node_id,
+            peers: Arc::new(peers),
+            last_seen: Arc::new(DashMap::new()),
+            reports: Arc::new(DashMap::new()),
+            member_timeout,
+        }
+    }
+
+    pub fn node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    pub fn peers(&self) -> &[Member] {
+        &self.peers
+    }
+
+    pub fn heartbeat(&self, node_id: u64) {
+        if node_id != self.node_id {
+            self.last_seen.insert(node_id, Instant::now());
+        }
+    }
+
+    /// Note the instances a peer's health report covered.
+    pub fn record_reports<'a>(&self, node_id: u64, service_ids: impl IntoIterator<Item = &'a str>) {
+        let now = Instant::now();
+        for service_id in service_ids {
+            self.reports.insert(service_id.to_string(), (node_id, now));
+        }
+        self.reports
+            .retain(|_, (_, reported)| reported.elapsed() < self.member_timeout);
+    }
+
+    /// Whether this node should check `service_id`: it owns it on the
+    /// ring, or the owner is not reporting on it. The registry is
+    /// node-local, so the owner may not hold the instance at all; the
+    /// nodes that do then check it themselves rather than leave it
+    /// unchecked.
+    pub fn should_check(&self, ring: &HashRing, service_id: &str) -> bool {
+        match ring.owner(service_id) {
+            Some(owner) if owner != self.node_id => {
+                !self.reports.get(service_id).is_some_and(|report| {
+                    report.0 == owner && report.1.elapsed() < self.member_timeout
+                })
+            }
+            _ => true,
+        }
+    }
+
+    fn is_alive(&self, node_id: u64) -> bool {
+        node_id == self.node_id
+            || self
+                .last_seen
+                .get(&node_id)
+                .map(|seen| seen.elapsed() < self.member_timeout)
+                .unwrap_or(false)
+    }
+
+    pub fn live_members(&self) -> Vec<u64> {
+        let mut members: Vec<u64> = std::iter::once(self.node_id)
+            .chain(self.peers.iter().map(|peer| peer.id))
+            .filter(|id| self.is_alive(*id))
+            .collect();
+        members.sort_unstable();
+        members.dedup();
+        members
+    }
+
+    pub fn members(&self) -> Vec<MemberStatus> {
+        std::iter::once(MemberStatus {
+            id: self.node_id,
+            address: None,
+            alive: true,
+        })
+        .chain(self.peers.iter().map(|peer| MemberStatus {
+            id: peer.id,
+            address: Some(peer.address.clone()),
+            alive: self.is_alive(peer.id),
+        }))
+        .collect()
+    }
+
+    /// Ring over the currently live members; rebuilt per check round so
+    /// work moves as soon as a peer times out or reappears.
+    pub fn ring(&self) -> HashRing {
+        HashRing::new(&self.live_members())
+    }
+}
@@ -0,0 +1,46 @@
+const VIRTUAL_NODES: u32 = 64;
+
+/// Stable 64-bit hash (FNV-1a with a splitmix finalizer) so every node
+/// places keys identically regardless of build or platform.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Consistent hash ring over cluster node ids.
+pub struct HashRing {
+    points: Vec<(u64, u64)>,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[u64]) -> Self {
+        let mut points: Vec<(u64, u64)> = nodes
+            .iter()
+            .flat_map(|node| {
+                (0..VIRTUAL_NODES).map(move |i| (hash(format!("{}-{}", node, i).as_bytes()), *node))
+            })
+            .collect();
+        points.sort_unstable();
+
+        Self { points }
+    }
+
+    pub fn owner(&self, key: &str) -> Option<u64> {
+        if self.points.is_empty() {
+            return None;
+        }
+
+        let point = hash(key.as_bytes());
+        let idx = self.points.partition_point(|(p, _)| *p < point);
+        Some(self.points[idx % self.points.len()].1)
+    }
+}
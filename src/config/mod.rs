@@ -1,6 +1,10 @@
 use config::{Config, ConfigError, File};
-use serde::Deserialize;
-use std::{net::IpAddr, path::PathBuf, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, time::Duration};
+
+use crate::cluster::Member;
+use crate::router::{HashOn, StrategyKind};
+use crate::service::Locality;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
@@ -8,6 +12,85 @@ pub struct ServerConfig {
     pub port: u16,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProxyConfig {
+    pub host: IpAddr,
+    pub port: u16,
+    pub path_prefix: String,
+    /// TOML file of `[[routes]]` applied to the route table at startup.
+    /// Routes since deleted through the API are skipped.
+    #[serde(default)]
+    pub routes_file: Option<PathBuf>,
+    /// PEM bundle of CA certificates trusted for `h2` (HTTP/2 over TLS)
+    /// upstreams.
+    #[serde(default)]
+    pub upstream_ca: Option<PathBuf>,
+    /// Raw TCP listeners, bound on `host`.
+    #[serde(default)]
+    pub tcp: Vec<TcpListenerConfig>,
+    /// TLS passthrough listener routing on the SNI name, bound on `host`.
+    #[serde(default)]
+    pub sni: Option<SniListenerConfig>,
+}
+
+/// Balances every connection on `port` to an instance of `service`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TcpListenerConfig {
+    pub port: u16,
+    pub service: String,
+    /// Milliseconds without traffic either way before a connection is
+    /// closed. By default connections stay open until either side
+    /// closes them, as pooled database connections expect.
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+}
+
+impl TcpListenerConfig {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_millis)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SniListenerConfig {
+    pub port: u16,
+    /// Service by server name: exact, or `*.example.com` for any
+    /// subdomain. Names not listed go to the service named by their
+    /// first label, as with Host routing.
+    #[serde(default)]
+    pub services: HashMap<String, String>,
+    /// As for TCP listeners: no idle timeout unless set.
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+}
+
+impl SniListenerConfig {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_millis)
+    }
+
+    pub fn service_for(&self, server_name: &str) -> Option<String> {
+        if let Some(service) = self.services.get(server_name) {
+            return Some(service.clone());
+        }
+        let wildcard = self
+            .services
+            .iter()
+            .filter_map(|(pattern, service)| Some((pattern.strip_prefix("*.")?, service)))
+            .filter(|(domain, _)| {
+                server_name.len() > domain.len() + 1
+                    && server_name.ends_with(domain)
+                    && server_name.as_bytes()[server_name.len() - domain.len() - 1] == b'.'
+            })
+            .max_by_key(|(domain, _)| domain.len());
+        if let Some((_, service)) = wildcard {
+            return Some(service.clone());
+        }
+        let label = server_name.split('.').next()?;
+        (!label.is_empty()).then(|| label.to_string())
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct SecurityConfig {
     pub jwt_secret: String,
@@ -24,9 +107,327 @@ pub struct RaftConfig {
 }
 
 #[derive(Debug, Deserialize, Clone)]
+pub struct ClusterConfig {
+    pub gossip_interval: u64,
+    pub member_timeout: u64,
+    pub members: Vec<Member>,
+    /// Where this node runs, for locality-aware routing.
+    #[serde(default)]
+    pub locality: Locality,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoadBalancerConfig {
+    pub strategy: StrategyKind,
+    #[serde(default)]
+    pub services: HashMap<String, StrategyKind>,
+    /// Request attribute `ring_hash` and `maglev` key on.
+    #[serde(default)]
+    pub hash_on: Option<HashOn>,
+    /// Cookie pinning clients to the instance that first served them.
+    #[serde(default)]
+    pub sticky_cookie: Option<String>,
+    #[serde(default)]
+    pub slow_start: SlowStartConfig,
+    #[serde(default)]
+    pub locality: LocalityConfig,
+}
+
+/// Locality-aware routing, active when `[cluster.locality]` is set.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LocalityConfig {
+    /// Healthy share of capacity, by weight, the closest localities must
+    /// keep before traffic spills over to the next ones.
+    pub min_healthy_percent: u8,
+}
+
+impl Default for LocalityConfig {
+    fn default() -> Self {
+        Self {
+            min_healthy_percent: 70,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowStartCurve {
+    Linear,
+    Exponential,
+}
+
+/// Ramp-up for instances that have just entered rotation. A `window` of 0
+/// disables it.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SlowStartConfig {
+    pub window: u64,
+    pub curve: SlowStartCurve,
+    /// Share of full weight an instance gets at the start of the window.
+    pub min_weight_percent: u8,
+}
+
+impl Default for SlowStartConfig {
+    fn default() -> Self {
+        Self {
+            window: 0,
+            curve: SlowStartCurve::Linear,
+            min_weight_percent: 10,
+        }
+    }
+}
+
+impl SlowStartConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct CircuitBreakerConfig {
-    pub failure_threshold: usize,
+    /// Length of the rolling window outcomes are counted over, in seconds.
+    pub window: u64,
+    /// Calls the window must hold before the breaker may trip.
+    pub minimum_calls: u32,
+    pub failure_rate_threshold: u8,
+    /// Calls taking at least this many milliseconds count as slow.
+    pub slow_call_duration: u64,
+    pub slow_call_rate_threshold: u8,
+    /// Seconds the breaker stays open before letting trial calls through.
     pub reset_timeout: u64,
+    /// Trial calls allowed while half-open; all must succeed to close.
+    pub half_open_max_calls: u32,
+    /// Per-service overrides, keyed by service name.
+    #[serde(default)]
+    pub services: HashMap<String, CircuitBreakerOverride>,
+}
+
+/// Circuit breaker settings that replace the global ones where set.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct CircuitBreakerOverride {
+    pub window: Option<u64>,
+    pub minimum_calls: Option<u32>,
+    pub failure_rate_threshold: Option<u8>,
+    pub slow_call_duration: Option<u64>,
+    pub slow_call_rate_threshold: Option<u8>,
+    pub reset_timeout: Option<u64>,
+    pub half_open_max_calls: Option<u32>,
+}
+
+impl CircuitBreakerOverride {
+    pub fn apply_to(&self, config: &mut CircuitBreakerConfig) {
+        config.window = self.window.unwrap_or(config.window);
+        config.minimum_calls = self.minimum_calls.unwrap_or(config.minimum_calls);
+        config.failure_rate_threshold =
+            self.failure_rate_threshold.unwrap_or(config.failure_rate_threshold);
+        config.slow_call_duration = self.slow_call_duration.unwrap_or(config.slow_call_duration);
+        config.slow_call_rate_threshold =
+            self.slow_call_rate_threshold.unwrap_or(config.slow_call_rate_threshold);
+        config.reset_timeout = self.reset_timeout.unwrap_or(config.reset_timeout);
+        config.half_open_max_calls = self.half_open_max_calls.unwrap_or(config.half_open_max_calls);
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window)
+    }
+
+    pub fn slow_call_duration(&self) -> Duration {
+        Duration::from_millis(self.slow_call_duration)
+    }
+
+    pub fn reset_timeout(&self) -> Duration {
+        Duration::from_secs(self.reset_timeout)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OutlierDetectionConfig {
+    pub interval: u64,
+    pub consecutive_failures: u32,
+    pub failure_percentage: u64,
+    pub minimum_requests: u64,
+    pub base_ejection_time: u64,
+    pub max_ejection_time: u64,
+    pub max_ejection_percent: usize,
+}
+
+/// When and how the proxy retries a failed request on another instance.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first.
+    pub attempts: u32,
+    /// Upstream response statuses worth another attempt.
+    pub retry_on_status: Vec<u16>,
+    /// Retry when no connection could be made; nothing reached the
+    /// upstream, so this applies to every method.
+    pub retry_on_connect_error: bool,
+    /// Also retry methods that are not idempotent, such as POST.
+    pub retry_non_idempotent: bool,
+    /// Backoff before the first retry, in milliseconds; doubles after each.
+    pub backoff_base: u64,
+    pub backoff_max: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            retry_on_status: vec![502, 503, 504],
+            retry_on_connect_error: true,
+            retry_non_idempotent: false,
+            backoff_base: 25,
+            backoff_max: 250,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn backoff_base(&self) -> Duration {
+        Duration::from_millis(self.backoff_base)
+    }
+
+    pub fn backoff_max(&self) -> Duration {
+        Duration::from_millis(self.backoff_max)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetryConfig {
+    /// Retries in flight may not exceed this share of active requests...
+    pub budget_percent: u8,
+    /// ...unless fewer than this many are in flight.
+    pub min_retry_concurrency: usize,
+    #[serde(default)]
+    pub default: RetryPolicy,
+    /// Per-service policies, keyed by service name.
+    #[serde(default)]
+    pub services: HashMap<String, RetryPolicy>,
+}
+
+impl RetryConfig {
+    pub fn policy_for(&self, service: &str) -> &RetryPolicy {
+        self.services.get(service).unwrap_or(&self.default)
+    }
+}
+
+/// Upstream timeouts for proxied requests, in milliseconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TimeoutPolicy {
+    /// Establishing a connection to an instance.
+    pub connect: u64,
+    /// From receiving the request to upstream response headers, retries
+    /// included. A shorter deadline sent by the caller takes precedence.
+    pub request: u64,
+    /// Longest gap between chunks of the response body.
+    pub idle: u64,
+}
+
+impl Default for TimeoutPolicy {
+    fn default() -> Self {
+        Self {
+            connect: 1000,
+            request: 15000,
+            idle: 60000,
+        }
+    }
+}
+
+impl TimeoutPolicy {
+    pub fn connect(&self) -> Duration {
+        Duration::from_millis(self.connect)
+    }
+
+    pub fn request(&self) -> Duration {
+        Duration::from_millis(self.request)
+    }
+
+    pub fn idle(&self) -> Duration {
+        Duration::from_millis(self.idle)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TimeoutConfig {
+    #[serde(default)]
+    pub default: TimeoutPolicy,
+    /// Per-service policies, keyed by service name.
+    #[serde(default)]
+    pub services: HashMap<String, TimeoutPolicy>,
+}
+
+impl TimeoutConfig {
+    pub fn policy_for(&self, service: &str) -> &TimeoutPolicy {
+        self.services.get(service).unwrap_or(&self.default)
+    }
+}
+
+/// Upstream connections kept for one instance.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PoolPolicy {
+    /// Open connections, idle or busy. HTTP/2 requests share one
+    /// connection.
+    pub max_connections: usize,
+    /// Idle HTTP/1.1 connections kept for reuse.
+    pub max_idle: usize,
+    /// Milliseconds an idle connection is kept open.
+    pub idle_timeout: u64,
+}
+
+impl Default for PoolPolicy {
+    fn default() -> Self {
+        Self {
+            max_connections: 256,
+            max_idle: 32,
+            idle_timeout: 90000,
+        }
+    }
+}
+
+impl PoolPolicy {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.idle_timeout)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ConnectionPoolConfig {
+    #[serde(default)]
+    pub default: PoolPolicy,
+    /// Per-service policies, keyed by service name.
+    #[serde(default)]
+    pub services: HashMap<String, PoolPolicy>,
+}
+
+impl ConnectionPoolConfig {
+    pub fn policy_for(&self, service: &str) -> &PoolPolicy {
+        self.services.get(service).unwrap_or(&self.default)
+    }
+}
+
+/// Name lookups cached by `RouteCache`. Registry changes invalidate
+/// entries straight away; the TTLs are a backstop.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouteCacheConfig {
+    /// Seconds an entry lives at most.
+    pub ttl: u64,
+    /// Seconds an unread entry lives.
+    pub idle: u64,
+    /// Service names cached at once.
+    pub capacity: u64,
+}
+
+impl RouteCacheConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
+
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -38,9 +439,17 @@ pub struct RateLimitConfig {
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub server: ServerConfig,
+    pub proxy: ProxyConfig,
     pub security: SecurityConfig,
     pub raft: RaftConfig,
+    pub cluster: ClusterConfig,
+    pub load_balancer: LoadBalancerConfig,
     pub circuit_breaker: CircuitBreakerConfig,
+    pub outlier_detection: OutlierDetectionConfig,
+    pub retry: RetryConfig,
+    pub timeouts: TimeoutConfig,
+    pub connection_pool: ConnectionPoolConfig,
+    pub route_cache: RouteCacheConfig,
     pub rate_limit: RateLimitConfig,
 }
 
@@ -52,11 +461,62 @@ impl Settings {
         config.merge(File::with_name("config/default"))?;
         config.merge(File::with_name("config/local").required(false))?;
 
-        config.deserialize()
+        let settings: Self = config.deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Reject settings that would panic or misbehave at runtime.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Message(message));
+        let outliers = &self.outlier_detection;
+        if outliers.interval == 0 {
+            return invalid("outlier_detection.interval must be greater than 0".to_string());
+        }
+        if outliers.failure_percentage > 100 || outliers.max_ejection_percent > 100 {
+            return invalid("outlier_detection percentages must be at most 100".to_string());
+        }
+        let pools = &self.connection_pool;
+        for (name, policy) in std::iter::once(("default".to_string(), &pools.default)).chain(
+            pools
+                .services
+                .iter()
+                .map(|(name, policy)| (format!("services.{}", name), policy)),
+        ) {
+            if policy.max_connections == 0 {
+                return invalid(format!(
+                    "connection_pool.{}: max_connections must be greater than 0",
+                    name
+                ));
+            }
+        }
+        let raft = &self.raft;
+        if raft.heartbeat_interval == 0 || raft.election_timeout < 2 * raft.heartbeat_interval {
+            return invalid(
+                "raft.election_timeout must be at least twice a non-zero raft.heartbeat_interval"
+                    .to_string(),
+            );
+        }
+        if let Some(peer) = raft
+            .peers
+            .iter()
+            .find(|peer| !self.cluster.members.iter().any(|member| member.id == **peer))
+        {
+            return invalid(format!("raft peer {} has no address in cluster.members", peer));
+        }
+        Ok(())
     }
 
     pub fn circuit_breaker_reset_timeout(&self) -> Duration {
-        Duration::from_secs(self.circuit_breaker.reset_timeout)
+        self.circuit_breaker.reset_timeout()
+    }
+
+    pub fn gossip_interval(&self) -> Duration {
+        Duration::from_secs(self.cluster.gossip_interval)
+    }
+
+    pub fn member_timeout(&self) -> Duration {
+        Duration::from_secs(self.cluster.member_timeout)
     }
 
     pub fn raft_election_timeout(&self) -> Duration {
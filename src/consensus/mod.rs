@@ -1,5 +1,6 @@
 mod raft;
 mod state;
+mod transport;
 
-pub use raft::RaftNode;
-pub use state::RaftStorage;
+pub use raft::{propose, run_raft, RaftNode, StateMachine};
+pub use transport::{decode, Transport};
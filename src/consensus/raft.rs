@@ -1,68 +1,215 @@
+use protobuf::Message as _;
 use raft::{
     prelude::*,
-    Config, RawNode, StateRole,
+    Config, RawNode, StateRole, INVALID_ID,
 };
 use slog::Logger;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify, RwLock};
+use crate::config::RaftConfig;
+use crate::prelude::*;
 use crate::store::Store;
 use super::state::RaftStorage;
+use super::transport::Transport;
+
+/// Longest a caller waits for its proposal to be applied.
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Replicated state, changed only by applying committed commands, in
+/// log order, on every node.
+pub trait StateMachine: Send + Sync {
+    /// Apply one command. Errors must be deterministic, since every node
+    /// applies the same command; the proposer's caller receives them.
+    fn apply(&self, command: &[u8]) -> Result<()>;
+}
 
 pub struct RaftNode {
-    id: u64,
     node: RawNode<RaftStorage>,
-    store: Arc<Store>,
-    logger: Logger,
+    state_machine: Arc<dyn StateMachine>,
+    /// Callers waiting for their proposals, by the id carried in the
+    /// entry context.
+    pending: HashMap<u64, oneshot::Sender<Result<()>>>,
+    /// Woken when a step or proposal leaves work for the ready loop.
+    wake: Arc<Notify>,
 }
 
 impl RaftNode {
     pub fn new(
-        id: u64,
-        peers: Vec<u64>,
-        store: Arc<Store>,
-        logger: Logger,
-    ) -> raft::Result<Self> {
+        config: &RaftConfig,
+        store: &Store,
+        state_machine: Arc<dyn StateMachine>,
+        logger: &Logger,
+    ) -> Result<Self> {
+        let voters = std::iter::once(config.node_id)
+            .chain(config.peers.iter().copied())
+            .collect();
+        let storage = RaftStorage::new(store, voters)?;
+
+        // One tick per heartbeat interval, so followers call an election
+        // once `election_timeout` passes without hearing from a leader.
         let config = Config {
-            id,
-            election_tick: 10,
-            heartbeat_tick: 3,
+            id: config.node_id,
+            election_tick: (config.election_timeout / config.heartbeat_interval) as usize,
+            heartbeat_tick: 1,
+            applied: storage.applied()?,
+            check_quorum: true,
+            pre_vote: true,
             ..Default::default()
         };
+        let node = RawNode::new(&config, storage, logger)?;
 
-        let storage = RaftStorage::new(store.clone(), logger.clone());
-        let node = RawNode::new(&config, storage, &logger)?;
-
-        Ok(Self { 
-            id,
+        Ok(Self {
             node,
-            store,
-            logger,
+            state_machine,
+            pending: HashMap::new(),
+            wake: Arc::new(Notify::new()),
         })
     }
 
-    pub async fn tick(&mut self) {
+    pub fn tick(&mut self) {
         self.node.tick();
     }
 
-    pub async fn propose(&mut self, data: Vec<u8>) -> raft::Result<()> {
-        self.node.propose(vec![], data)?;
-        Ok(())
+    /// Propose a command, to be told once it has been applied here.
+    /// Followers forward it to the leader.
+    fn propose(&mut self, command: Vec<u8>) -> Result<oneshot::Receiver<Result<()>>> {
+        let id = rand::random::<u64>();
+        self.node
+            .propose(id.to_be_bytes().to_vec(), command)
+            .map_err(|e| match e {
+                raft::Error::ProposalDropped => {
+                    Error::Unavailable("no Raft leader to take the change".to_string())
+                }
+                e => e.into(),
+            })?;
+        let (applied, receiver) = oneshot::channel();
+        self.pending.insert(id, applied);
+        self.wake.notify_one();
+        Ok(receiver)
     }
 
-    pub async fn step(&mut self, msg: Message) -> raft::Result<()> {
+    pub fn step(&mut self, msg: Message) -> Result<()> {
         self.node.step(msg)?;
+        self.wake.notify_one();
         Ok(())
     }
 
-    pub async fn advance(&mut self) {
-        self.node.advance(Ready::default());
+    pub fn is_leader(&self) -> bool {
+        self.node.raft.state == StateRole::Leader
+    }
+
+    pub fn leader_id(&self) -> Option<u64> {
+        let leader = self.node.raft.leader_id;
+        (leader != INVALID_ID).then_some(leader)
+    }
+
+    pub fn applied_index(&self) -> u64 {
+        self.node.raft.raft_log.applied
+    }
+
+    pub fn committed_index(&self) -> u64 {
+        self.node.raft.raft_log.committed
     }
 
-    pub async fn campaign(&mut self) -> raft::Result<()> {
-        self.node.campaign()?;
+    /// Persist and apply whatever Raft has ready, returning the messages
+    /// to send to peers.
+    fn process_ready(&mut self) -> Result<Vec<Message>> {
+        // Proposals lost to a leader change are never applied; their
+        // callers have given up by now.
+        self.pending.retain(|_, applied| !applied.is_closed());
+        if !self.node.has_ready() {
+            return Ok(Vec::new());
+        }
+        let mut ready = self.node.ready();
+        let mut messages = ready.take_messages();
+
+        self.apply(ready.take_committed_entries())?;
+        let store = self.node.store();
+        store.append(ready.entries())?;
+        if let Some(hard_state) = ready.hs() {
+            store.set_hard_state(hard_state)?;
+        }
+        store.flush()?;
+        messages.extend(ready.take_persisted_messages());
+
+        let mut light = self.node.advance(ready);
+        if let Some(commit) = light.commit_index() {
+            self.node.store().set_commit(commit)?;
+        }
+        messages.extend(light.take_messages());
+        self.apply(light.take_committed_entries())?;
+        self.node.advance_apply();
+        self.node.store().flush()?;
+        Ok(messages)
+    }
+
+    fn apply(&mut self, entries: Vec<Entry>) -> Result<()> {
+        for entry in entries {
+            match entry.get_entry_type() {
+                EntryType::EntryConfChange if !entry.data.is_empty() => {
+                    let change = ConfChange::parse_from_bytes(&entry.data)
+                        .map_err(|e| Error::Storage(e.to_string()))?;
+                    let conf_state = self.node.apply_conf_change(&change)?;
+                    self.node.store().set_conf_state(&conf_state)?;
+                }
+                EntryType::EntryConfChangeV2 if !entry.data.is_empty() => {
+                    let change = ConfChangeV2::parse_from_bytes(&entry.data)
+                        .map_err(|e| Error::Storage(e.to_string()))?;
+                    let conf_state = self.node.apply_conf_change(&change)?;
+                    self.node.store().set_conf_state(&conf_state)?;
+                }
+                EntryType::EntryNormal if !entry.data.is_empty() => {
+                    let result = self.state_machine.apply(&entry.data);
+                    let waiting = <[u8; 8]>::try_from(entry.context.as_ref())
+                        .ok()
+                        .and_then(|id| self.pending.remove(&u64::from_be_bytes(id)));
+                    match (waiting, result) {
+                        (Some(applied), result) => {
+                            let _ = applied.send(result);
+                        }
+                        (None, Err(e)) => {
+                            tracing::warn!("Failed to apply Raft entry {}: {}", entry.index, e)
+                        }
+                        (None, Ok(())) => {}
+                    }
+                }
+                // A new leader's empty entry only needs to be marked applied.
+                _ => {}
+            }
+            self.node.store().set_applied(entry.index)?;
+        }
         Ok(())
     }
+}
 
-    pub fn is_leader(&self) -> bool {
-        self.node.raft.state == StateRole::Leader
+/// Replicate `command` and wait until this node has applied it.
+pub async fn propose(raft: &RwLock<RaftNode>, command: Vec<u8>) -> Result<()> {
+    let applied = raft.write().await.propose(command)?;
+    match tokio::time::timeout(PROPOSAL_TIMEOUT, applied).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) | Err(_) => Err(Error::Unavailable(
+            "change was not committed in time".to_string(),
+        )),
     }
-}
\ No newline at end of file
+}
+
+/// Drive the node: tick it every `tick`, and persist, apply and send
+/// whatever it has ready after each tick, step or proposal.
+pub async fn run_raft(raft: Arc<RwLock<RaftNode>>, transport: Transport, tick: Duration) {
+    let wake = raft.read().await.wake.clone();
+    let mut ticker = tokio::time::interval(tick);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => raft.write().await.tick(),
+            _ = wake.notified() => {}
+        }
+
+        match raft.write().await.process_ready() {
+            Ok(messages) => transport.send(messages),
+            Err(e) => tracing::error!("Failed to process Raft ready state: {}", e),
+        }
+    }
+}
@@ -1,32 +1,125 @@
-use raft::prelude::*; // This includes all the necessary eraftpb types
-use raft::{GetEntriesContext, RaftState, Result as RaftResult, Storage};
-use slog::Logger;
-use std::sync::Arc;
+use raft::prelude::*;
+use raft::util::limit_size;
+use raft::{GetEntriesContext, RaftState, Result as RaftResult, Storage, StorageError};
+use sled::Tree;
+
+use crate::prelude::*;
 use crate::store::Store;
 
+const ENTRIES_TREE: &str = "raft_entries";
+const STATE_TREE: &str = "raft_state";
+const HARD_STATE_KEY: &[u8] = b"hard_state";
+const CONF_STATE_KEY: &[u8] = b"conf_state";
+const APPLIED_KEY: &[u8] = b"applied";
+
+/// The Raft log and its state, kept in sled so a restarted node rejoins
+/// where it left off. Entries are keyed by big-endian index, so the tree
+/// iterates in log order. The log is never compacted.
 pub struct RaftStorage {
-    store: Arc<Store>,
-    hard_state: HardState,
-    snapshot: Snapshot,
-    logger: Logger,
+    entries: Tree,
+    state: Tree,
 }
 
 impl RaftStorage {
-    pub fn new(store: Arc<Store>, logger: Logger) -> Self {
-        Self {
-            store,
-            hard_state: HardState::default(),
-            snapshot: Snapshot::default(),
-            logger,
+    /// Open the stored log. A node starting for the first time takes
+    /// `voters` as the cluster configuration.
+    pub fn new(store: &Store, voters: Vec<u64>) -> Result<Self> {
+        let storage = Self {
+            entries: store.tree(ENTRIES_TREE)?,
+            state: store.tree(STATE_TREE)?,
+        };
+        if storage.state.get(CONF_STATE_KEY)?.is_none() {
+            storage.set_conf_state(&ConfState::from((voters, Vec::new())))?;
+        }
+        Ok(storage)
+    }
+
+    /// Write entries from a `Ready`, replacing any conflicting tail.
+    pub fn append(&self, entries: &[Entry]) -> Result<()> {
+        let Some(first) = entries.first() else {
+            return Ok(());
+        };
+        for key in self.entries.range(first.index.to_be_bytes()..).keys() {
+            self.entries.remove(key?)?;
+        }
+        for entry in entries {
+            self.entries.insert(entry.index.to_be_bytes(), encode(entry)?)?;
         }
+        Ok(())
+    }
+
+    pub fn set_hard_state(&self, hard_state: &HardState) -> Result<()> {
+        self.state.insert(HARD_STATE_KEY, encode(hard_state)?)?;
+        Ok(())
+    }
+
+    pub fn set_commit(&self, commit: u64) -> Result<()> {
+        let mut hard_state = self.hard_state()?;
+        hard_state.commit = commit;
+        self.set_hard_state(&hard_state)
+    }
+
+    pub fn set_conf_state(&self, conf_state: &ConfState) -> Result<()> {
+        self.state.insert(CONF_STATE_KEY, encode(conf_state)?)?;
+        Ok(())
+    }
+
+    /// Index of the last entry applied, so a restart doesn't apply it again.
+    pub fn applied(&self) -> Result<u64> {
+        Ok(self
+            .state
+            .get(APPLIED_KEY)?
+            .and_then(|bytes| bytes.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0))
+    }
+
+    pub fn set_applied(&self, applied: u64) -> Result<()> {
+        self.state.insert(APPLIED_KEY, &applied.to_be_bytes()[..])?;
+        Ok(())
+    }
+
+    /// Make everything written so far durable.
+    pub fn flush(&self) -> Result<()> {
+        self.entries.flush()?;
+        self.state.flush()?;
+        Ok(())
+    }
+
+    fn hard_state(&self) -> Result<HardState> {
+        self.state
+            .get(HARD_STATE_KEY)?
+            .map_or_else(|| Ok(HardState::default()), |bytes| decode(&bytes))
+    }
+
+    fn conf_state(&self) -> Result<ConfState> {
+        self.state
+            .get(CONF_STATE_KEY)?
+            .map_or_else(|| Ok(ConfState::default()), |bytes| decode(&bytes))
+    }
+
+    fn entry(&self, index: u64) -> Result<Option<Entry>> {
+        self.entries
+            .get(index.to_be_bytes())?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    fn last(&self) -> Result<u64> {
+        Ok(self
+            .entries
+            .last()?
+            .and_then(|(key, _)| key.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0))
     }
 }
 
 impl Storage for RaftStorage {
     fn initial_state(&self) -> RaftResult<RaftState> {
         Ok(RaftState {
-            hard_state: self.hard_state.clone(),
-            conf_state: ConfState::default(),
+            hard_state: self.hard_state().map_err(storage_error)?,
+            conf_state: self.conf_state().map_err(storage_error)?,
         })
     }
 
@@ -35,15 +128,30 @@ impl Storage for RaftStorage {
         low: u64,
         high: u64,
         max_size: impl Into<Option<u64>>,
-        context: GetEntriesContext,
+        _context: GetEntriesContext,
     ) -> RaftResult<Vec<Entry>> {
-        // Implement entry retrieval from storage
-        Ok(vec![])
+        if high > self.last().map_err(storage_error)? + 1 {
+            return Err(raft::Error::Store(StorageError::Unavailable));
+        }
+        let mut entries = self
+            .entries
+            .range(low.to_be_bytes()..high.to_be_bytes())
+            .values()
+            .map(|bytes| decode(&bytes?))
+            .collect::<Result<Vec<Entry>>>()
+            .map_err(storage_error)?;
+        limit_size(&mut entries, max_size.into());
+        Ok(entries)
     }
 
     fn term(&self, idx: u64) -> RaftResult<u64> {
-        // Implement term lookup
-        Ok(0)
+        if idx == 0 {
+            return Ok(0);
+        }
+        match self.entry(idx).map_err(storage_error)? {
+            Some(entry) => Ok(entry.term),
+            None => Err(raft::Error::Store(StorageError::Unavailable)),
+        }
     }
 
     fn first_index(&self) -> RaftResult<u64> {
@@ -51,10 +159,66 @@ impl Storage for RaftStorage {
     }
 
     fn last_index(&self) -> RaftResult<u64> {
-        Ok(1)
+        self.last().map_err(storage_error)
+    }
+
+    /// Nothing is compacted, so peers always catch up from the log.
+    fn snapshot(&self, _request_index: u64, _to: u64) -> RaftResult<Snapshot> {
+        Err(raft::Error::Store(StorageError::SnapshotTemporarilyUnavailable))
     }
+}
+
+fn encode(message: &impl protobuf::Message) -> Result<Vec<u8>> {
+    message
+        .write_to_bytes()
+        .map_err(|e| Error::Storage(e.to_string()))
+}
+
+fn decode<M: protobuf::Message>(bytes: &[u8]) -> Result<M> {
+    M::parse_from_bytes(bytes).map_err(|e| Error::Storage(e.to_string()))
+}
+
+fn storage_error(error: Error) -> raft::Error {
+    raft::Error::Store(StorageError::Other(Box::new(error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn appends_replace_the_conflicting_tail_and_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("lodestone-raft-{}", uuid::Uuid::new_v4()));
+        {
+            let store = Store::new(&path).unwrap();
+            let storage = RaftStorage::new(&store, vec![1, 2, 3]).unwrap();
+            storage.append(&[entry(1, 1), entry(2, 1), entry(3, 1)]).unwrap();
+            storage.append(&[entry(2, 2)]).unwrap();
+            storage.set_applied(2).unwrap();
+            storage.flush().unwrap();
+        }
+
+        let store = Store::new(&path).unwrap();
+        let storage = RaftStorage::new(&store, vec![1]).unwrap();
+        assert_eq!(storage.last_index().unwrap(), 2);
+        assert_eq!(storage.term(2).unwrap(), 2);
+        assert!(storage.term(3).is_err());
+        let entries = storage.entries(1, 3, None, GetEntriesContext::empty(false)).unwrap();
+        assert_eq!(entries.iter().map(|e| e.term).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(storage.applied().unwrap(), 2);
+        // The first start's configuration is kept.
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.conf_state.voters, vec![1, 2, 3]);
 
-    fn snapshot(&self, request_index: u64, to: u64) -> RaftResult<Snapshot> {
-        Ok(self.snapshot.clone())
+        drop((storage, store));
+        std::fs::remove_dir_all(path).ok();
     }
 }
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use protobuf::Message as _;
+use raft::prelude::Message;
+
+use crate::cluster::Member;
+use crate::prelude::*;
+
+/// Longest a peer gets to accept a message. Raft resends anything lost.
+const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Carries Raft messages to peers as protobuf POSTed to their
+/// `/raft/message` endpoint, at the addresses in `[[cluster.members]]`.
+#[derive(Clone)]
+pub struct Transport {
+    client: reqwest::Client,
+    addresses: Arc<HashMap<u64, String>>,
+}
+
+impl Transport {
+    pub fn new(members: &[Member]) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            addresses: Arc::new(
+                members
+                    .iter()
+                    .map(|member| (member.id, member.address.clone()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Send without waiting for delivery.
+    pub fn send(&self, messages: Vec<Message>) {
+        for message in messages {
+            let to = message.to;
+            let Some(address) = self.addresses.get(&to) else {
+                tracing::warn!("No address for Raft peer {}", to);
+                continue;
+            };
+            let body = match message.write_to_bytes() {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::warn!("Failed to encode Raft message for node {}: {}", to, e);
+                    continue;
+                }
+            };
+            let request = self
+                .client
+                .post(format!("http://{}/raft/message", address))
+                .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+                .body(body)
+                .timeout(SEND_TIMEOUT)
+                .send();
+            tokio::spawn(async move {
+                if let Err(e) = request.await.and_then(|response| response.error_for_status()) {
+                    tracing::debug!("Failed to send Raft message to node {}: {}", to, e);
+                }
+            });
+        }
+    }
+}
+
+/// A message as a peer's transport sent it.
+pub fn decode(body: &[u8]) -> Result<Message> {
+    Message::parse_from_bytes(body).map_err(|e| Error::BadRequest(e.to_string()))
+}
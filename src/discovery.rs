@@ -1,23 +1,57 @@
-use crate::health::{HealthCheck, HealthStatus};
+use crate::cluster::Membership;
+use crate::health::{AggregateHealth, Aggregator, HealthCheck, HealthStatus, HealthTable, ServiceGroup};
 // src/discovery/mod.rs
 use crate::prelude::*;
-use crate::service::Service;
+use crate::router::{BreakerState, RouteCache};
+use crate::service::{Maintenance, Service};
 use crate::store::Store;
 use reqwest;
+use serde::Serialize;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tokio::time::{self, Duration};
+use tokio::sync::{broadcast, RwLock};
+use tokio::time::{self, Duration, Instant};
 
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+const GROUPS_TREE: &str = "groups";
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Change notification published on every registry mutation and health
+/// transition.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RegistryEvent {
+    Registered { service: Service },
+    Updated { service: Service },
+    Deregistered { id: String, name: String },
+    HealthChanged { id: String, status: HealthStatus },
+    BreakerChanged {
+        service: String,
+        id: String,
+        from: BreakerState,
+        to: BreakerState,
+    },
+}
+
+#[derive(Clone)]
 pub struct ServiceRegistry {
     store: Arc<Store>,
-    health_checks: RwLock<Vec<String>>,
+    health: HealthTable,
+    membership: Membership,
+    events: broadcast::Sender<RegistryEvent>,
+    cache: RouteCache,
+    last_check_round: Arc<RwLock<Option<Instant>>>,
 }
 
 impl ServiceRegistry {
-    pub fn new(store: Arc<Store>) -> Self {
+    pub fn new(store: Arc<Store>, membership: Membership, cache: RouteCache) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
         let registry = Self {
             store,
-            health_checks: RwLock::new(Vec::new()),
+            health: HealthTable::new(events.clone()),
+            membership,
+            events,
+            cache,
+            last_check_round: Arc::new(RwLock::new(None)),
         };
 
         // Spawn health check task
@@ -27,25 +61,89 @@ impl ServiceRegistry {
     }
 
     pub async fn register(&self, service: Service) -> Result<()> {
+        service.validate()?;
+        let existing = self.store.get(&service.id)?;
         self.store.set(&service.id, &service)?;
 
-        let mut health_checks = self.health_checks.write().await;
-        health_checks.push(service.id.clone());
+        match existing {
+            Some(previous) => {
+                if previous.name != service.name {
+                    self.cache.invalidate(&previous.name).await;
+                }
+                self.publish(RegistryEvent::Updated { service }).await
+            }
+            None => self.publish(RegistryEvent::Registered { service }).await,
+        }
 
         Ok(())
     }
 
     pub async fn deregister(&self, service_id: &str) -> Result<()> {
+        let existing = self.store.get(service_id)?;
         self.store.delete(service_id)?;
 
-        let mut health_checks = self.health_checks.write().await;
-        if let Some(pos) = health_checks.iter().position(|id| id == service_id) {
-            health_checks.remove(pos);
+        self.health.remove(service_id);
+
+        if let Some(service) = existing {
+            self.publish(RegistryEvent::Deregistered {
+                id: service.id,
+                name: service.name,
+            })
+            .await;
         }
 
         Ok(())
     }
 
+    /// Put an instance into (or, with `None`, take it out of) maintenance.
+    /// It stays registered but receives no traffic until the maintenance
+    /// is lifted or expires.
+    pub async fn set_maintenance(
+        &self,
+        service_id: &str,
+        maintenance: Option<Maintenance>,
+    ) -> Result<Service> {
+        self.update(service_id, |service| service.maintenance = maintenance).await
+    }
+
+    /// Stop routing new traffic to an instance while letting in-flight
+    /// requests finish.
+    pub async fn set_draining(&self, service_id: &str, draining: bool) -> Result<Service> {
+        self.update(service_id, |service| service.draining = draining).await
+    }
+
+    async fn update(&self, service_id: &str, apply: impl FnOnce(&mut Service)) -> Result<Service> {
+        let mut service = self
+            .store
+            .get(service_id)?
+            .ok_or_else(|| Error::ServiceNotFound(service_id.to_string()))?;
+        apply(&mut service);
+        self.store.set(service_id, &service)?;
+
+        self.publish(RegistryEvent::Updated {
+            service: service.clone(),
+        })
+        .await;
+        Ok(service)
+    }
+
+    /// Invalidates cached lookups before subscribers hear of the change.
+    async fn publish(&self, event: RegistryEvent) {
+        self.cache.apply(&event).await;
+        // No subscribers is fine; the event is simply dropped.
+        let _ = self.events.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RegistryEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sender for components outside the registry that publish on the same
+    /// stream, such as circuit breakers.
+    pub fn events(&self) -> broadcast::Sender<RegistryEvent> {
+        self.events.clone()
+    }
+
     pub async fn get_service(&self, service_id: &str) -> Result<Option<Service>> {
         self.store.get(service_id)
     }
@@ -55,27 +153,128 @@ impl ServiceRegistry {
     }
 
     pub async fn get_services_by_name(&self, name: &str) -> Result<Vec<Service>> {
-        self.store.scan_prefix(&format!("service:{}", name))
+        self.cache
+            .get_or_load(name, || {
+                let services: Vec<Service> = self.store.list()?;
+                Ok(services.into_iter().filter(|service| service.name == name).collect())
+            })
+            .await
+    }
+
+    pub fn cache(&self) -> RouteCache {
+        self.cache.clone()
+    }
+
+    /// Instances of `name` that may take new traffic: not known to be
+    /// unhealthy (by active checks or outlier ejection), not in maintenance
+    /// and not draining.
+    pub async fn get_healthy_services_by_name(&self, name: &str) -> Result<Vec<Service>> {
+        let services = self.get_services_by_name(name).await?;
+        Ok(services
+            .into_iter()
+            .filter(|service| {
+                service.is_available() && self.health.status(&service.id) != HealthStatus::Unhealthy
+            })
+            .collect())
+    }
+
+    pub async fn set_group(&self, group: ServiceGroup) -> Result<()> {
+        self.store.set_in(GROUPS_TREE, &group.name, &group)
+    }
+
+    pub async fn get_group(&self, name: &str) -> Result<Option<ServiceGroup>> {
+        self.store.get_from(GROUPS_TREE, name)
+    }
+
+    pub async fn list_groups(&self) -> Result<Vec<ServiceGroup>> {
+        self.store.list_in(GROUPS_TREE)
+    }
+
+    pub async fn delete_group(&self, name: &str) -> Result<()> {
+        self.store.delete_from(GROUPS_TREE, name)
+    }
+
+    /// Service-level health for `name`, rolled up from its instances and
+    /// its declared dependencies.
+    pub async fn aggregate_health(&self, name: &str) -> Result<AggregateHealth> {
+        let services = self.store.list()?;
+        let groups = self.list_groups().await?;
+        let aggregator = Aggregator::new(&services, &groups, |service| self.health_of(service));
+
+        if !aggregator.is_known(name) {
+            return Err(Error::ServiceNotFound(name.to_string()));
+        }
+        Ok(aggregator.aggregate(name))
+    }
+
+    /// When the health checker last started a round, if it ever has.
+    pub async fn last_check_round(&self) -> Option<Instant> {
+        *self.last_check_round.read().await
+    }
+
+    pub fn health(&self) -> HealthTable {
+        self.health.clone()
+    }
+
+    /// Health as reported to clients: maintenance and draining take
+    /// precedence over check results.
+    pub fn health_of(&self, service: &Service) -> HealthCheck {
+        if let Some(maintenance) = service.maintenance.as_ref().filter(|m| m.is_active()) {
+            return HealthCheck {
+                status: HealthStatus::Maintenance,
+                message: Some(maintenance.reason.clone()),
+                timestamp: chrono::Utc::now(),
+            };
+        }
+        if service.draining {
+            return HealthCheck {
+                status: HealthStatus::Draining,
+                message: None,
+                timestamp: chrono::Utc::now(),
+            };
+        }
+        self.health.get(&service.id)
     }
 
     async fn run_health_checks(self) {
-        let mut interval = time::interval(Duration::from_secs(10));
+        let mut interval = time::interval(HEALTH_CHECK_INTERVAL);
 
         loop {
             interval.tick().await;
+            *self.last_check_round.write().await = Some(Instant::now());
 
-            let health_checks = self.health_checks.read().await;
-            for service_id in health_checks.iter() {
-                if let Ok(Some(service)) = self.store.get(service_id) {
-                    let health = self.check_health(&service).await;
-                    if health.status == HealthStatus::Unhealthy {
-                        tracing::warn!(
-                            "Service {} failed health check: {:?}",
-                            service_id,
-                            health.message
-                        );
-                    }
+            // Check every instance this node holds that it owns on the
+            // ring, or whose owner isn't reporting on it; peers report the
+            // rest through gossip.
+            let ring = self.membership.ring();
+            let services = match self.store.list() {
+                Ok(services) => services,
+                Err(e) => {
+                    tracing::error!("Failed to load services for health checks: {}", e);
+                    continue;
+                }
+            };
+            for service in services {
+                let service_id = &service.id;
+                // The registry is node-local, so every node clears the
+                // windows it holds, whoever runs the check.
+                if service.maintenance.is_some() && !service.in_maintenance() {
+                    tracing::info!("Maintenance window for {} expired", service_id);
+                    let _ = self.set_maintenance(service_id, None).await;
+                }
+                if !self.membership.should_check(&ring, service_id) {
+                    continue;
                 }
+
+                let health = self.check_health(&service).await;
+                if health.status == HealthStatus::Unhealthy {
+                    tracing::warn!(
+                        "Service {} failed health check: {:?}",
+                        service_id,
+                        health.message
+                    );
+                }
+                self.health.record(service_id, health);
             }
         }
     }
@@ -99,12 +298,3 @@ impl ServiceRegistry {
         }
     }
 }
-
-impl Clone for ServiceRegistry {
-    fn clone(&self) -> Self {
-        Self {
-            store: Arc::clone(&self.store),
-            health_checks: RwLock::new(Vec::new()),
-        }
-    }
-}
@@ -4,6 +4,8 @@ use std::path::PathBuf;
 pub enum TlsConfigurationError {
     #[error("Private key not found at path: {0}")]
     PrivateKeyNotFound(PathBuf),
+    #[error("No certificates found at path: {0}")]
+    CertificatesNotFound(PathBuf),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -14,10 +16,22 @@ pub enum Error {
     Storage(String),
     #[error("Service not found: {0}")]
     ServiceNotFound(String),
+    #[error("No healthy instances of service: {0}")]
+    NoHealthyInstance(String),
+    #[error("Upstream error: {0}")]
+    Upstream(String),
+    #[error("Circuit open for service: {0}")]
+    CircuitOpen(String),
+    #[error("Upstream timed out: {0}")]
+    Timeout(String),
+    #[error("Too many connections for route: {0}")]
+    TooManyConnections(String),
     #[error("Invalid request: {0}")]
     BadRequest(String),
     #[error("Raft error: {0}")]
     Raft(#[from] raft::Error),
+    #[error("Cluster unavailable: {0}")]
+    Unavailable(String),
     #[error("Auth error: {0}")]
     Auth(String),
     #[error("Rate limit exceeded")]
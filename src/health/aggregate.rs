@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::{HealthCheck, HealthStatus};
+use crate::service::Service;
+
+fn default_min_healthy_percent() -> u8 {
+    50
+}
+
+/// Service-level definition sitting alongside the registered instances of
+/// a service name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceGroup {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    #[serde(default = "default_min_healthy_percent")]
+    pub min_healthy_percent: u8,
+}
+
+impl ServiceGroup {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            dependencies: Vec::new(),
+            min_healthy_percent: default_min_healthy_percent(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AggregateHealth {
+    pub name: String,
+    pub status: HealthStatus,
+    pub message: Option<String>,
+    pub healthy_instances: usize,
+    pub total_instances: usize,
+    pub min_healthy_percent: u8,
+    pub instances: BTreeMap<String, HealthCheck>,
+    pub dependencies: BTreeMap<String, HealthStatus>,
+}
+
+/// Rolls instance health up to service level and follows declared
+/// dependencies. A service whose own instances pass but whose dependencies
+/// fail is reported as `Degraded`.
+pub struct Aggregator<'a, F> {
+    instances: HashMap<&'a str, Vec<&'a Service>>,
+    groups: HashMap<&'a str, &'a ServiceGroup>,
+    health_of: F,
+}
+
+impl<'a, F> Aggregator<'a, F>
+where
+    F: Fn(&Service) -> HealthCheck,
+{
+    pub fn new(services: &'a [Service], groups: &'a [ServiceGroup], health_of: F) -> Self {
+        let mut instances: HashMap<&str, Vec<&Service>> = HashMap::new();
+        for service in services {
+            instances
+                .entry(service.name.as_str())
+                .or_default()
+                .push(service);
+        }
+
+        Self {
+            instances,
+            groups: groups
+                .iter()
+                .map(|group| (group.name.as_str(), group))
+                .collect(),
+            health_of,
+        }
+    }
+
+    pub fn is_known(&self, name: &str) -> bool {
+        self.instances.contains_key(name) || self.groups.contains_key(name)
+    }
+
+    pub fn aggregate(&self, name: &str) -> AggregateHealth {
+        let mut visiting = HashSet::new();
+        self.aggregate_inner(name, &mut visiting)
+    }
+
+    fn aggregate_inner(&self, name: &str, visiting: &mut HashSet<String>) -> AggregateHealth {
+        visiting.insert(name.to_string());
+
+        let default_group = ServiceGroup::new(name);
+        let group = self.groups.get(name).copied().unwrap_or(&default_group);
+
+        let instances: BTreeMap<String, HealthCheck> = self
+            .instances
+            .get(name)
+            .map(|services| {
+                services
+                    .iter()
+                    .map(|service| (service.id.clone(), (self.health_of)(service)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let total = instances.len();
+        let healthy = instances
+            .values()
+            .filter(|check| check.status == HealthStatus::Healthy)
+            .count();
+
+        let mut dependencies = BTreeMap::new();
+        for dependency in &group.dependencies {
+            // A dependency cycle would otherwise recurse forever; treat the
+            // back edge as unknown rather than failing the whole graph.
+            let status = if visiting.contains(dependency) {
+                HealthStatus::Unknown
+            } else {
+                self.aggregate_inner(dependency, visiting).status
+            };
+            dependencies.insert(dependency.clone(), status);
+        }
+        visiting.remove(name);
+
+        let failed: Vec<&str> = dependencies
+            .iter()
+            .filter(|(_, status)| {
+                matches!(status, HealthStatus::Unhealthy | HealthStatus::Degraded)
+            })
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        let (status, message) = if total == 0 {
+            (
+                HealthStatus::Unhealthy,
+                Some("no registered instances".to_string()),
+            )
+        } else if healthy * 100 < total * group.min_healthy_percent as usize {
+            (
+                HealthStatus::Unhealthy,
+                Some(format!(
+                    "{} of {} instances healthy, need {}%",
+                    healthy, total, group.min_healthy_percent
+                )),
+            )
+        } else if !failed.is_empty() {
+            (
+                HealthStatus::Degraded,
+                Some(format!("dependencies failing: {}", failed.join(", "))),
+            )
+        } else {
+            (HealthStatus::Healthy, None)
+        };
+
+        AggregateHealth {
+            name: name.to_string(),
+            status,
+            message,
+            healthy_instances: healthy,
+            total_instances: total,
+            min_healthy_percent: group.min_healthy_percent,
+            instances,
+            dependencies,
+        }
+    }
+}
@@ -0,0 +1,131 @@
+mod aggregate;
+mod node;
+mod outlier;
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::discovery::RegistryEvent;
+
+pub use aggregate::{AggregateHealth, Aggregator, ServiceGroup};
+pub use node::{NodeHealth, NodeHealthReport};
+pub use outlier::{OutlierDetector, Outcome};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub status: HealthStatus,
+    pub message: Option<String>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HealthStatus {
+    Healthy,
+    Unhealthy,
+    Unknown,
+    Degraded,
+    Maintenance,
+    Draining,
+}
+
+#[derive(Debug, Clone)]
+struct Ejection {
+    until: DateTime<Utc>,
+    reason: String,
+}
+
+/// Per-instance health shared by the active checker and passive outlier
+/// detection. An active ejection always wins over the last check result.
+#[derive(Debug, Clone)]
+pub struct HealthTable {
+    checks: Arc<DashMap<String, HealthCheck>>,
+    ejections: Arc<DashMap<String, Ejection>>,
+    events: broadcast::Sender<RegistryEvent>,
+}
+
+impl HealthTable {
+    pub fn new(events: broadcast::Sender<RegistryEvent>) -> Self {
+        Self {
+            checks: Arc::new(DashMap::new()),
+            ejections: Arc::new(DashMap::new()),
+            events,
+        }
+    }
+
+    pub fn record(&self, service_id: &str, check: HealthCheck) {
+        let before = self.status(service_id);
+        self.checks.insert(service_id.to_string(), check);
+        self.notify(service_id, before);
+    }
+
+    pub fn eject(&self, service_id: &str, until: DateTime<Utc>, reason: String) {
+        let before = self.status(service_id);
+        self.ejections
+            .insert(service_id.to_string(), Ejection { until, reason });
+        self.notify(service_id, before);
+    }
+
+    pub fn restore(&self, service_id: &str) {
+        if self.ejections.remove(service_id).is_some() {
+            self.notify(service_id, HealthStatus::Unhealthy);
+        }
+    }
+
+    fn notify(&self, service_id: &str, before: HealthStatus) {
+        let status = self.status(service_id);
+        if status != before {
+            // No subscribers is fine; the event is simply dropped.
+            let _ = self.events.send(RegistryEvent::HealthChanged {
+                id: service_id.to_string(),
+                status,
+            });
+        }
+    }
+
+    pub fn is_ejected(&self, service_id: &str) -> bool {
+        self.ejections
+            .get(service_id)
+            .map(|ejection| ejection.until > Utc::now())
+            .unwrap_or(false)
+    }
+
+    pub fn remove(&self, service_id: &str) {
+        self.checks.remove(service_id);
+        self.ejections.remove(service_id);
+    }
+
+    /// Last active check result, ignoring any outlier ejection.
+    pub fn check(&self, service_id: &str) -> Option<HealthCheck> {
+        self.checks.get(service_id).map(|check| check.clone())
+    }
+
+    pub fn get(&self, service_id: &str) -> HealthCheck {
+        if let Some(ejection) = self.ejections.get(service_id) {
+            if ejection.until > Utc::now() {
+                return HealthCheck {
+                    status: HealthStatus::Unhealthy,
+                    message: Some(ejection.reason.clone()),
+                    timestamp: Utc::now(),
+                };
+            }
+        }
+
+        self.checks
+            .get(service_id)
+            .map(|check| check.clone())
+            .unwrap_or_else(|| HealthCheck {
+                status: HealthStatus::Unknown,
+                message: None,
+                timestamp: Utc::now(),
+            })
+    }
+
+    pub fn status(&self, service_id: &str) -> HealthStatus {
+        self.get(service_id).status
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use super::{HealthCheck, HealthStatus};
+use crate::consensus::RaftNode;
+use crate::discovery::{ServiceRegistry, HEALTH_CHECK_INTERVAL};
+use crate::security::CertificateValidity;
+use crate::store::Store;
+
+/// Rounds the health checker may miss before it counts as stalled.
+const MISSED_CHECK_ROUNDS: u32 = 3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeHealthReport {
+    pub status: HealthStatus,
+    pub components: BTreeMap<&'static str, HealthCheck>,
+}
+
+impl NodeHealthReport {
+    fn from_components(components: BTreeMap<&'static str, HealthCheck>) -> Self {
+        let status = if components
+            .values()
+            .all(|check| check.status == HealthStatus::Healthy)
+        {
+            HealthStatus::Healthy
+        } else {
+            HealthStatus::Unhealthy
+        };
+
+        Self { status, components }
+    }
+}
+
+/// Health of this Lodestone node itself, as opposed to the services it
+/// tracks.
+#[derive(Clone)]
+pub struct NodeHealth {
+    store: Arc<Store>,
+    raft: Arc<RwLock<RaftNode>>,
+    registry: ServiceRegistry,
+    certificate: Option<CertificateValidity>,
+}
+
+impl NodeHealth {
+    pub fn new(
+        store: Arc<Store>,
+        raft: Arc<RwLock<RaftNode>>,
+        registry: ServiceRegistry,
+        certificate: Option<CertificateValidity>,
+    ) -> Self {
+        Self {
+            store,
+            raft,
+            registry,
+            certificate,
+        }
+    }
+
+    /// The process is up and serving requests.
+    pub fn liveness(&self) -> NodeHealthReport {
+        NodeHealthReport::from_components(BTreeMap::new())
+    }
+
+    /// The node can serve registry reads and writes.
+    pub async fn readiness(&self) -> NodeHealthReport {
+        let mut components = BTreeMap::new();
+        components.insert("store", self.check_store());
+        components.insert("raft", self.check_raft().await);
+        NodeHealthReport::from_components(components)
+    }
+
+    /// Everything readiness covers, plus background work and TLS.
+    pub async fn health(&self) -> NodeHealthReport {
+        let mut components = BTreeMap::new();
+        components.insert("store", self.check_store());
+        components.insert("raft", self.check_raft().await);
+        components.insert("health_checker", self.check_health_checker().await);
+        components.insert("tls", self.check_tls());
+        NodeHealthReport::from_components(components)
+    }
+
+    fn check_store(&self) -> HealthCheck {
+        match self.store.check() {
+            Ok(()) => healthy(None),
+            Err(e) => unhealthy(e.to_string()),
+        }
+    }
+
+    async fn check_raft(&self) -> HealthCheck {
+        let raft = self.raft.read().await;
+        let (applied, committed) = (raft.applied_index(), raft.committed_index());
+
+        match raft.leader_id() {
+            None => unhealthy("no known raft leader".to_string()),
+            Some(_) if applied < committed => unhealthy(format!(
+                "applied index {} behind committed index {}",
+                applied, committed
+            )),
+            Some(leader) => healthy(Some(format!(
+                "leader {}, applied index {}",
+                leader, applied
+            ))),
+        }
+    }
+
+    async fn check_health_checker(&self) -> HealthCheck {
+        match self.registry.last_check_round().await {
+            None => unhealthy("health checker has not run".to_string()),
+            Some(last) if last.elapsed() > HEALTH_CHECK_INTERVAL * MISSED_CHECK_ROUNDS => {
+                unhealthy(format!(
+                    "last check round {}s ago",
+                    last.elapsed().as_secs()
+                ))
+            }
+            Some(last) => healthy(Some(format!(
+                "last check round {}s ago",
+                last.elapsed().as_secs()
+            ))),
+        }
+    }
+
+    fn check_tls(&self) -> HealthCheck {
+        let now = Utc::now();
+        match self.certificate {
+            None => unhealthy("certificate validity could not be read".to_string()),
+            Some(validity) if now < validity.not_before => unhealthy(format!(
+                "certificate not valid before {}",
+                validity.not_before
+            )),
+            Some(validity) if now > validity.not_after => {
+                unhealthy(format!("certificate expired at {}", validity.not_after))
+            }
+            Some(validity) => healthy(Some(format!(
+                "certificate valid until {}",
+                validity.not_after
+            ))),
+        }
+    }
+}
+
+fn healthy(message: Option<String>) -> HealthCheck {
+    HealthCheck {
+        status: HealthStatus::Healthy,
+        message,
+        timestamp: Utc::now(),
+    }
+}
+
+fn unhealthy(message: String) -> HealthCheck {
+    HealthCheck {
+        status: HealthStatus::Unhealthy,
+        message: Some(message),
+        timestamp: Utc::now(),
+    }
+}
@@ -0,0 +1,277 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use dashmap::DashMap;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::HealthTable;
+use crate::config::OutlierDetectionConfig;
+use crate::discovery::{RegistryEvent, ServiceRegistry};
+use crate::service::Service;
+
+/// Result of a single proxied request, as seen by outlier detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    ServerError,
+    ConnectError,
+}
+
+#[derive(Debug, Default)]
+struct InstanceStats {
+    service: String,
+    requests: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    times_ejected: u32,
+}
+
+/// Passive health detection driven by proxied traffic.
+///
+/// Instances are ejected either after `consecutive_failures` 5xx/connect
+/// errors in a row, or when their failure percentage over an interval
+/// crosses `failure_percentage`. Each repeat ejection doubles the ejection
+/// time up to `max_ejection_time`.
+pub struct OutlierDetector {
+    config: OutlierDetectionConfig,
+    health: HealthTable,
+    stats: DashMap<String, InstanceStats>,
+    /// Service of every registered instance, so a pool counts instances
+    /// that have not seen traffic yet.
+    instances: DashMap<String, String>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: OutlierDetectionConfig, health: HealthTable) -> Self {
+        Self {
+            config,
+            health,
+            stats: DashMap::new(),
+            instances: DashMap::new(),
+        }
+    }
+
+    pub fn record(&self, service: &Service, outcome: Outcome) {
+        let trip = {
+            let mut stats = self.stats.entry(service.id.clone()).or_default();
+            stats.service.clone_from(&service.name);
+            stats.requests += 1;
+
+            if outcome == Outcome::Success {
+                stats.consecutive_failures = 0;
+                false
+            } else {
+                stats.failures += 1;
+                stats.consecutive_failures += 1;
+                stats.consecutive_failures >= self.config.consecutive_failures
+            }
+        };
+
+        if trip {
+            self.eject(&service.id, "consecutive failures");
+        }
+    }
+
+    /// Sweep every interval, and forget instances as they are
+    /// deregistered so they no longer count toward their pool's size.
+    pub async fn run(&self, registry: ServiceRegistry) {
+        // Subscribe before the initial load so nothing slips in between.
+        let mut events = registry.subscribe();
+        self.resync(&registry).await;
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval));
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(RegistryEvent::Registered { service } | RegistryEvent::Updated { service }) => {
+                        self.instances.insert(service.id, service.name);
+                    }
+                    Ok(RegistryEvent::Deregistered { id, .. }) => {
+                        self.stats.remove(&id);
+                        self.instances.remove(&id);
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Outlier detection missed {} registry events, resyncing", skipped);
+                        self.resync(&registry).await;
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = interval.tick() => self.sweep(),
+            }
+        }
+    }
+
+    async fn resync(&self, registry: &ServiceRegistry) {
+        match registry.list_services().await {
+            Ok(services) => {
+                self.stats
+                    .retain(|id, _| services.iter().any(|service| &service.id == id));
+                self.instances.clear();
+                for service in services {
+                    self.instances.insert(service.id, service.name);
+                }
+            }
+            Err(e) => tracing::error!("Failed to load services for outlier detection: {}", e),
+        }
+    }
+
+    fn sweep(&self) {
+        let mut candidates = Vec::new();
+
+        for mut entry in self.stats.iter_mut() {
+            let id = entry.key().clone();
+            let stats = entry.value_mut();
+
+            if self.health.is_ejected(&id) {
+                continue;
+            }
+
+            // Any lapsed ejection is lifted, and a clean interval lets the
+            // ejection backoff decay again.
+            self.health.restore(&id);
+            if stats.failures == 0 {
+                stats.times_ejected = stats.times_ejected.saturating_sub(1);
+            }
+
+            if stats.requests >= self.config.minimum_requests
+                && stats.failures * 100 >= stats.requests * self.config.failure_percentage
+            {
+                candidates.push(id);
+            }
+
+            stats.requests = 0;
+            stats.failures = 0;
+        }
+
+        for id in candidates {
+            self.eject(&id, "failure percentage");
+        }
+    }
+
+    fn eject(&self, service_id: &str, reason: &str) {
+        if self.health.is_ejected(service_id) {
+            return;
+        }
+
+        let Some(service) = self
+            .stats
+            .get(service_id)
+            .map(|stats| stats.service.clone())
+        else {
+            return;
+        };
+
+        // Never eject more than max_ejection_percent of a pool, nor its last
+        // instance, but otherwise allow at least one so a single bad
+        // instance can be taken out.
+        let mut pool: Vec<String> = self
+            .instances
+            .iter()
+            .filter(|entry| *entry.value() == service)
+            .map(|entry| entry.key().clone())
+            .chain(
+                self.stats
+                    .iter()
+                    .filter(|entry| entry.service == service)
+                    .map(|entry| entry.key().clone()),
+            )
+            .collect();
+        pool.sort();
+        pool.dedup();
+        let ejected = pool.iter().filter(|id| self.health.is_ejected(id)).count();
+        let allowed = (pool.len() * self.config.max_ejection_percent / 100)
+            .max(1)
+            .min(pool.len().saturating_sub(1));
+        if ejected >= allowed {
+            tracing::warn!(
+                "Not ejecting {}: {} of {} instances of {} already ejected",
+                service_id,
+                ejected,
+                pool.len(),
+                service
+            );
+            return;
+        }
+
+        let Some(mut stats) = self.stats.get_mut(service_id) else {
+            return;
+        };
+        let multiplier = 1u64 << stats.times_ejected.min(16);
+        let duration =
+            (self.config.base_ejection_time * multiplier).min(self.config.max_ejection_time);
+        stats.times_ejected += 1;
+        stats.consecutive_failures = 0;
+
+        tracing::warn!(
+            "Ejecting {} ({}) for {}s: {}",
+            service_id,
+            service,
+            duration,
+            reason
+        );
+        self.health.eject(
+            service_id,
+            Utc::now() + chrono::Duration::seconds(duration as i64),
+            format!("ejected by outlier detection: {}", reason),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    fn detector() -> OutlierDetector {
+        let config = OutlierDetectionConfig {
+            interval: 10,
+            consecutive_failures: 2,
+            failure_percentage: 50,
+            minimum_requests: 20,
+            base_ejection_time: 30,
+            max_ejection_time: 300,
+            max_ejection_percent: 100,
+        };
+        OutlierDetector::new(config, HealthTable::new(broadcast::channel(16).0))
+    }
+
+    fn instance(detector: &OutlierDetector, id: &str) -> Service {
+        let mut service = Service::new("orders".to_string(), "127.0.0.1".to_string(), 8000);
+        service.id = id.to_string();
+        detector.instances.insert(service.id.clone(), service.name.clone());
+        service
+    }
+
+    fn fail(detector: &OutlierDetector, service: &Service) {
+        detector.record(service, Outcome::ServerError);
+        detector.record(service, Outcome::ServerError);
+    }
+
+    #[test]
+    fn never_ejects_a_single_instance_pool() {
+        let detector = detector();
+        let only = instance(&detector, "a");
+        fail(&detector, &only);
+        assert!(!detector.health.is_ejected("a"));
+    }
+
+    #[test]
+    fn keeps_the_last_instance_of_a_pool() {
+        let detector = detector();
+        let (a, b) = (instance(&detector, "a"), instance(&detector, "b"));
+        fail(&detector, &a);
+        fail(&detector, &b);
+        assert!(detector.health.is_ejected("a"));
+        assert!(!detector.health.is_ejected("b"));
+    }
+
+    #[test]
+    fn counts_instances_without_traffic() {
+        let detector = detector();
+        let a = instance(&detector, "a");
+        instance(&detector, "b");
+        fail(&detector, &a);
+        assert!(detector.health.is_ejected("a"));
+    }
+}
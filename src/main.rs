@@ -5,6 +5,7 @@ use axum::serve;
 use tokio::net::TcpListener;
 use slog::{Logger, Drain};
 
+mod cluster;
 mod config;
 mod consensus;
 mod discovery;
@@ -13,14 +14,18 @@ mod security;
 mod store;
 mod prelude;
 mod health;
+mod proxy;
 mod service;
 mod error;
 
+use crate::cluster::{run_gossip, Membership};
 use crate::config::Settings;
-use crate::consensus::RaftNode;
+use crate::consensus::{run_raft, RaftNode, Transport};
 use crate::discovery::ServiceRegistry;
-use crate::router::Router;
-use crate::security::TlsConfig;
+use crate::health::{NodeHealth, OutlierDetector};
+use crate::proxy::{read_routes_file, L4Proxy, Proxy, RouteTable, UpstreamClient};
+use crate::router::{CircuitBreakers, LoadBalancer, RouteCache, Router};
+use crate::security::{upstream_connector, TlsConfig};
 use crate::store::Store;
 use crate::prelude::*;
 
@@ -64,16 +69,54 @@ async fn main() -> Result<()> {
     // Initialize the storage layer
     let store = Arc::new(Store::new("data")?);
     
+    // Declarative routes, replicated as the Raft state machine
+    let file_routes = settings
+        .proxy
+        .routes_file
+        .as_deref()
+        .map(read_routes_file)
+        .transpose()?;
+    let routes = RouteTable::load(store.clone())?;
+
     // Initialize Raft consensus
     let raft_node = Arc::new(RwLock::new(RaftNode::new(
-        settings.raft.node_id,
-        settings.raft.peers.clone(),
-        store.clone(),
-        logger.clone(),
+        &settings.raft,
+        &store,
+        Arc::new(routes.clone()),
+        &logger,
     )?));
     
+    // Initialize cluster membership used to split health checks
+    let membership = Membership::new(
+        settings.raft.node_id,
+        settings.cluster.members.clone(),
+        settings.member_timeout(),
+    );
+
     // Initialize the service registry
-    let registry = Arc::new(RwLock::new(ServiceRegistry::new(store.clone())));
+    let registry = Arc::new(RwLock::new(ServiceRegistry::new(
+        store.clone(),
+        membership.clone(),
+        RouteCache::new(&settings.route_cache),
+    )));
+
+    // Share owned health check results with the rest of the cluster
+    tokio::spawn(run_gossip(
+        membership.clone(),
+        registry.read().await.clone(),
+        settings.gossip_interval(),
+    ));
+
+    // Start passive outlier detection over the registry's health state
+    let outlier_detector = Arc::new(OutlierDetector::new(
+        settings.outlier_detection.clone(),
+        registry.read().await.health(),
+    ));
+    let detector_clone = outlier_detector.clone();
+    let detector_registry = registry.read().await.clone();
+    tokio::spawn(async move {
+        detector_clone.run(detector_registry).await;
+    });
     
     // Initialize TLS
     let tls_config = TlsConfig::new(
@@ -82,19 +125,103 @@ async fn main() -> Result<()> {
     )?;
     
     // Initialize the router with all features
-    let app = Router::new(registry.clone());
+    let node_health = NodeHealth::new(
+        store.clone(),
+        raft_node.clone(),
+        registry.read().await.clone(),
+        tls_config.validity(),
+    );
+    let breakers = CircuitBreakers::new(
+        settings.circuit_breaker.clone(),
+        registry.read().await.events(),
+    );
+
+    // Pooled upstream connections, closed as instances leave the registry
+    let upstream_tls = settings
+        .proxy
+        .upstream_ca
+        .as_ref()
+        .map(upstream_connector)
+        .transpose()?;
+    let upstream = UpstreamClient::new(upstream_tls, settings.connection_pool.clone());
+    tokio::spawn(upstream.pools().clone().sync(registry.read().await.clone()));
+
+    let app = Router::new(
+        registry.clone(),
+        raft_node.clone(),
+        membership.clone(),
+        node_health,
+        breakers.clone(),
+        routes.clone(),
+        upstream.clone(),
+    );
 
-    // Start Raft ticker
-    let raft_clone = raft_node.clone();
-    let settings_clone = settings.clone();
+    // Keep the load balancer's endpoints in step with the registry
+    let balancer = LoadBalancer::new(
+        settings.load_balancer.clone(),
+        settings.cluster.locality.clone(),
+        breakers.clone(),
+    );
+    tokio::spawn(balancer.clone().sync(registry.read().await.clone()));
+
+    // Initialize the proxy data plane
+    let proxy = Proxy::router(
+        routes.clone(),
+        upstream,
+        balancer.clone(),
+        outlier_detector.clone(),
+        breakers.clone(),
+        &settings,
+    );
+
+    // Drive Raft, exchanging messages with peers over their admin API
+    tokio::spawn(run_raft(
+        raft_node.clone(),
+        Transport::new(&settings.cluster.members),
+        settings.raft_heartbeat_interval(),
+    ));
+
+    // Propose routes file changes once the cluster has a leader
+    if let Some(file_routes) = file_routes {
+        tokio::spawn(routes.clone().sync_file(raft_node.clone(), file_routes));
+    }
+
+    // Start the proxy listener
+    let proxy_addr = SocketAddr::new(
+        settings.proxy.host,
+        settings.proxy.port,
+    );
+    tracing::info!("Starting proxy on {}", proxy_addr);
+    let proxy_listener = TcpListener::bind(proxy_addr).await?;
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(settings_clone.raft_heartbeat_interval());
-        loop {
-            interval.tick().await;
-            let mut _node = raft_clone.write().await;
+        if let Err(e) = serve(
+            proxy_listener,
+            proxy.into_make_service_with_connect_info::<SocketAddr>(),
+        ).await {
+            tracing::error!("Proxy listener failed: {}", e);
         }
     });
 
+    // Start the layer 4 listeners
+    let l4_proxy = L4Proxy::new(
+        balancer.clone(),
+        outlier_detector.clone(),
+        breakers,
+        settings.timeouts.clone(),
+    );
+    for tcp in settings.proxy.tcp.clone() {
+        let addr = SocketAddr::new(settings.proxy.host, tcp.port);
+        tracing::info!("Proxying TCP on {} to {}", addr, tcp.service);
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(l4_proxy.clone().serve_tcp(listener, tcp));
+    }
+    if let Some(sni) = settings.proxy.sni.clone() {
+        let addr = SocketAddr::new(settings.proxy.host, sni.port);
+        tracing::info!("Proxying TLS by SNI on {}", addr);
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(l4_proxy.serve_sni(listener, sni));
+    }
+
     // Start the HTTP server
     let addr = SocketAddr::new(
         settings.server.host,
@@ -0,0 +1,354 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::http::{header, uri::Scheme, HeaderMap, Uri, Version};
+use hyper::body::Incoming;
+use hyper::client::conn::{http1, http2};
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
+use tower::BoxError;
+
+use super::grpc;
+use super::pool::{ConnectionPools, InstancePool, PoolStats};
+use crate::config::ConnectionPoolConfig;
+use crate::router::ClassifyError;
+use crate::service::Service;
+
+/// Instance metadata naming the protocol the instance speaks: `http1`,
+/// `h2c` for HTTP/2 in cleartext, or `h2` for HTTP/2 over TLS.
+pub const PROTOCOL_KEY: &str = "lodestone.protocol";
+
+/// Instance metadata with the name to verify a TLS upstream's
+/// certificate against, when it differs from the instance address.
+pub const TLS_NAME_KEY: &str = "lodestone.tls_name";
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    #[error("connect to {0} failed: {1}")]
+    Connect(String, io::Error),
+    #[error("TLS to {0} unavailable: {1}")]
+    Tls(String, String),
+    #[error("connection pool for {0} is full")]
+    PoolExhausted(String),
+    #[error("request to {0} failed: {1}")]
+    Http(String, hyper::Error),
+}
+
+/// A full pool means the instance is busy, not broken, so it doesn't count
+/// against its breaker.
+impl ClassifyError for BoxError {
+    fn is_failure(&self) -> bool {
+        !matches!(self.downcast_ref::<UpstreamError>(), Some(UpstreamError::PoolExhausted(..)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Http1,
+    H2c,
+    H2,
+}
+
+impl Protocol {
+    /// gRPC needs HTTP/2, so gRPC calls to instances that don't say
+    /// otherwise go out as h2c.
+    fn for_request(instance: &Service, headers: &HeaderMap) -> Self {
+        match instance.metadata.get(PROTOCOL_KEY).map(String::as_str) {
+            Some("h2c") => Protocol::H2c,
+            Some("h2") => Protocol::H2,
+            Some(_) => Protocol::Http1,
+            None if grpc::is_grpc(headers) => Protocol::H2c,
+            None => Protocol::Http1,
+        }
+    }
+}
+
+/// Sends requests to upstream instances over pooled connections,
+/// streaming both bodies. HTTP/1.1 connections go back to the pool once
+/// the response body is done, and can be taken over with
+/// `hyper::upgrade::on` after a `101 Switching Protocols`. HTTP/2
+/// requests to an instance are multiplexed over one connection. Every
+/// request, including each call on a long-lived gRPC channel, picks its
+/// own instance, so no channel stays pinned to one backend.
+#[derive(Clone, Default)]
+pub struct UpstreamClient {
+    /// Set when a CA bundle for TLS upstreams is configured.
+    tls: Option<TlsConnector>,
+    pools: ConnectionPools,
+}
+
+impl UpstreamClient {
+    pub fn new(tls: Option<TlsConnector>, pools: ConnectionPoolConfig) -> Self {
+        Self {
+            tls,
+            pools: ConnectionPools::new(pools),
+        }
+    }
+
+    pub fn pools(&self) -> &ConnectionPools {
+        &self.pools
+    }
+
+    pub fn pool_stats(&self) -> Vec<PoolStats> {
+        self.pools.stats()
+    }
+
+    pub async fn send(
+        &self,
+        instance: &Service,
+        mut request: Request<Body>,
+        connect_timeout: Duration,
+    ) -> Result<Response<Incoming>, UpstreamError> {
+        let pool = self.pools.get(instance);
+        match Protocol::for_request(instance, request.headers()) {
+            Protocol::Http1 => {
+                // Clients may reach Lodestone over HTTP/2 and still be
+                // proxied to an HTTP/1.1 instance.
+                if request.version() == Version::HTTP_2 {
+                    *request.version_mut() = Version::HTTP_11;
+                }
+                Self::send_http1(&pool, instance, request, connect_timeout).await
+            }
+            protocol => {
+                self.send_http2(&pool, instance, protocol, request, connect_timeout)
+                    .await
+            }
+        }
+    }
+
+    async fn send_http1(
+        pool: &Arc<InstancePool>,
+        instance: &Service,
+        mut request: Request<Body>,
+        connect_timeout: Duration,
+    ) -> Result<Response<Incoming>, UpstreamError> {
+        let authority = format!("{}:{}", instance.address, instance.port);
+        loop {
+            let (mut sender, reused) = match pool.take_idle() {
+                Some(sender) => (sender, true),
+                None => {
+                    let permit = Self::permit(pool, &authority, connect_timeout).await?;
+                    let stream = Self::connect(&authority, connect_timeout).await?;
+                    let sender = Self::handshake_http1(stream, permit, &authority).await?;
+                    (sender, false)
+                }
+            };
+            match sender.try_send_request(request).await {
+                Ok(response) => {
+                    // Ready again once the response body is done; an
+                    // upgraded or closed connection never is.
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        if sender.ready().await.is_ok() {
+                            pool.put_idle(sender);
+                        }
+                    });
+                    return Ok(response);
+                }
+                Err(mut e) => match e.take_message() {
+                    // The idle connection closed before the request went
+                    // out, so it is safe to send it on another one.
+                    Some(message) if reused => request = message,
+                    _ => return Err(UpstreamError::Http(authority, e.into_error())),
+                },
+            }
+        }
+    }
+
+    async fn send_http2(
+        &self,
+        pool: &InstancePool,
+        instance: &Service,
+        protocol: Protocol,
+        request: Request<Body>,
+        connect_timeout: Duration,
+    ) -> Result<Response<Incoming>, UpstreamError> {
+        let authority = format!("{}:{}", instance.address, instance.port);
+        let scheme = match protocol {
+            Protocol::H2 => Scheme::HTTPS,
+            _ => Scheme::HTTP,
+        };
+        let mut request = Self::http2_request(request, scheme, &authority);
+        let mut fresh = false;
+        loop {
+            let shared = pool
+                .http2
+                .lock()
+                .unwrap()
+                .clone()
+                .filter(|sender| !fresh && !sender.is_closed());
+            let mut sender = match shared {
+                Some(sender) => {
+                    pool.count_reuse();
+                    sender
+                }
+                None => {
+                    // Connect without holding the slot, so requests to a
+                    // live connection never wait on a slow dial. Of
+                    // concurrent dials, the first to finish is shared.
+                    let opened = self
+                        .open_http2(pool, instance, protocol, &authority, connect_timeout)
+                        .await?;
+                    let mut slot = pool.http2.lock().unwrap();
+                    let sender = match slot.as_ref() {
+                        Some(sender) if !fresh && !sender.is_closed() => sender.clone(),
+                        _ => {
+                            *slot = Some(opened.clone());
+                            opened
+                        }
+                    };
+                    fresh = true;
+                    sender
+                }
+            };
+            match sender.try_send_request(request).await {
+                Ok(response) => return Ok(response),
+                Err(mut e) => match e.take_message() {
+                    // The shared connection was going away; open a new one.
+                    Some(message) if !fresh => {
+                        request = message;
+                        fresh = true;
+                    }
+                    _ => return Err(UpstreamError::Http(authority, e.into_error())),
+                },
+            }
+        }
+    }
+
+    /// HTTP/2 carries the target as `:scheme` and `:authority`, so the
+    /// request URI is made absolute, taking the authority from `Host`.
+    fn http2_request(mut request: Request<Body>, scheme: Scheme, authority: &str) -> Request<Body> {
+        let mut parts = request.uri().clone().into_parts();
+        parts.scheme = Some(scheme);
+        parts.authority = request
+            .headers_mut()
+            .remove(header::HOST)
+            .and_then(|host| host.to_str().ok()?.parse().ok())
+            .or_else(|| authority.parse().ok());
+        if parts.path_and_query.is_none() {
+            parts.path_and_query = Some("/".parse().expect("static path"));
+        }
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *request.uri_mut() = uri;
+        }
+        *request.version_mut() = Version::HTTP_2;
+        request
+    }
+
+    async fn permit(
+        pool: &InstancePool,
+        authority: &str,
+        wait: Duration,
+    ) -> Result<OwnedSemaphorePermit, UpstreamError> {
+        pool.permit(wait)
+            .await
+            .ok_or_else(|| UpstreamError::PoolExhausted(authority.to_string()))
+    }
+
+    async fn connect(
+        authority: &str,
+        connect_timeout: Duration,
+    ) -> Result<TcpStream, UpstreamError> {
+        tokio::time::timeout(connect_timeout, TcpStream::connect(authority))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")))
+            .map_err(|e| UpstreamError::Connect(authority.to_string(), e))
+    }
+
+    async fn open_http2(
+        &self,
+        pool: &InstancePool,
+        instance: &Service,
+        protocol: Protocol,
+        authority: &str,
+        connect_timeout: Duration,
+    ) -> Result<http2::SendRequest<Body>, UpstreamError> {
+        if protocol != Protocol::H2 {
+            let permit = Self::permit(pool, authority, connect_timeout).await?;
+            let stream = Self::connect(authority, connect_timeout).await?;
+            return Self::handshake_http2(stream, permit, authority).await;
+        }
+
+        let Some(tls) = &self.tls else {
+            return Err(UpstreamError::Tls(
+                authority.to_string(),
+                "no upstream_ca configured".to_string(),
+            ));
+        };
+        let name = instance
+            .metadata
+            .get(TLS_NAME_KEY)
+            .unwrap_or(&instance.address);
+        let server_name = ServerName::try_from(name.as_str())
+            .map_err(|e| UpstreamError::Tls(authority.to_string(), e.to_string()))?;
+        let permit = Self::permit(pool, authority, connect_timeout).await?;
+        let stream = Self::connect(authority, connect_timeout).await?;
+        let stream = tokio::time::timeout(connect_timeout, tls.connect(server_name, stream))
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "TLS handshake timed out",
+                ))
+            })
+            .map_err(|e| UpstreamError::Connect(authority.to_string(), e))?;
+        Self::handshake_http2(stream, permit, authority).await
+    }
+
+    /// The connection counts against the pool until it closes.
+    async fn handshake_http1(
+        stream: TcpStream,
+        permit: OwnedSemaphorePermit,
+        authority: &str,
+    ) -> Result<http1::SendRequest<Body>, UpstreamError> {
+        let (sender, connection) = http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|e| UpstreamError::Http(authority.to_string(), e))?;
+
+        let connection_authority = authority.to_string();
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = connection.with_upgrades().await {
+                tracing::debug!(
+                    "Upstream connection to {} closed: {}",
+                    connection_authority,
+                    e
+                );
+            }
+        });
+        Ok(sender)
+    }
+
+    async fn handshake_http2<T>(
+        stream: T,
+        permit: OwnedSemaphorePermit,
+        authority: &str,
+    ) -> Result<http2::SendRequest<Body>, UpstreamError>
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(|e| UpstreamError::Http(authority.to_string(), e))?;
+
+        let connection_authority = authority.to_string();
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = connection.await {
+                tracing::debug!(
+                    "Upstream connection to {} closed: {}",
+                    connection_authority,
+                    e
+                );
+            }
+        });
+        Ok(sender)
+    }
+}
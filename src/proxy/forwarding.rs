@@ -0,0 +1,195 @@
+use std::net::IpAddr;
+
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Uri};
+use uuid::Uuid;
+
+/// Identifies a request across Lodestone and its upstreams. Kept when the
+/// client sends one, generated otherwise, and echoed on the response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// The proxy listener only speaks plain HTTP.
+const PROTO: &str = "http";
+
+/// Headers that describe one connection rather than the message, and
+/// must not be passed on by a proxy (RFC 9110, section 7.6.1).
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Remove hop-by-hop headers, including any named in `Connection`.
+/// `TE: trailers` is kept, since gRPC needs it end to end. For an
+/// upgrade the `Upgrade` header is kept and `Connection: upgrade` put
+/// back, so the instance can switch protocols.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty() && (!upgrade || token != "upgrade"))
+        .collect();
+    for name in &named {
+        headers.remove(name.as_str());
+    }
+
+    let trailers = headers
+        .get_all(header::TE)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("trailers"));
+    for name in HOP_BY_HOP {
+        if upgrade && name == "upgrade" {
+            continue;
+        }
+        headers.remove(name);
+    }
+    if trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+    if upgrade {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+/// Tell the upstream who the client is and what it asked for, with both
+/// the `X-Forwarded-*` headers and `Forwarded` (RFC 7239). Client
+/// addresses are appended to any the client sent.
+pub fn add_forwarded(headers: &mut HeaderMap, uri: &Uri, client: IpAddr) {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| uri.authority().map(|authority| authority.as_str()))
+        .map(str::to_string);
+
+    append(
+        headers,
+        HeaderName::from_static(X_FORWARDED_FOR),
+        &client.to_string(),
+    );
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(PROTO));
+    if let Some(value) = host
+        .as_deref()
+        .and_then(|host| HeaderValue::from_str(host).ok())
+    {
+        headers.insert(X_FORWARDED_HOST, value);
+    }
+
+    let node = match client {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let mut element = format!("for={}", node);
+    if let Some(host) = &host {
+        element.push_str(&format!(";host=\"{}\"", host));
+    }
+    element.push_str(&format!(";proto={}", PROTO));
+    append(headers, header::FORWARDED, &element);
+}
+
+/// The request's id, generating one if the client sent none.
+pub fn ensure_request_id(headers: &mut HeaderMap) -> HeaderValue {
+    if let Some(id) = headers.get(REQUEST_ID_HEADER).filter(|id| !id.is_empty()) {
+        return id.clone();
+    }
+    let id = HeaderValue::from_str(&Uuid::new_v4().to_string()).expect("uuid is a valid header");
+    headers.insert(REQUEST_ID_HEADER, id.clone());
+    id
+}
+
+/// Add `value` to a comma-separated list header, joining repeated
+/// headers into one.
+fn append(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let mut list: Vec<&str> = headers
+        .get_all(&name)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    list.push(value);
+    if let Ok(value) = HeaderValue::from_str(&list.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn strips_hop_by_hop_and_connection_tokens() {
+        let mut headers = headers(&[
+            ("connection", "keep-alive, x-hop"),
+            ("keep-alive", "timeout=5"),
+            ("x-hop", "1"),
+            ("proxy-authorization", "secret"),
+            ("transfer-encoding", "chunked"),
+            ("te", "gzip"),
+            ("accept", "*/*"),
+        ]);
+        strip_hop_by_hop(&mut headers, false);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("accept"));
+    }
+
+    #[test]
+    fn keeps_te_trailers_and_upgrade() {
+        let mut headers = headers(&[
+            ("te", "trailers"),
+            ("connection", "Upgrade"),
+            ("upgrade", "websocket"),
+        ]);
+        strip_hop_by_hop(&mut headers, true);
+        assert_eq!(headers["te"], "trailers");
+        assert_eq!(headers["upgrade"], "websocket");
+        assert_eq!(headers["connection"], "upgrade");
+    }
+
+    #[test]
+    fn appends_forwarded_headers() {
+        let mut headers = headers(&[
+            ("host", "shop.example.com"),
+            ("x-forwarded-for", "10.0.0.1"),
+        ]);
+        let uri = Uri::from_static("/cart");
+        add_forwarded(&mut headers, &uri, "2001:db8::1".parse().unwrap());
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1, 2001:db8::1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "shop.example.com");
+        assert_eq!(
+            headers["forwarded"],
+            "for=\"[2001:db8::1]\";host=\"shop.example.com\";proto=http"
+        );
+    }
+
+    #[test]
+    fn keeps_or_generates_request_id() {
+        let mut sent = headers(&[("x-request-id", "abc-123")]);
+        assert_eq!(ensure_request_id(&mut sent), "abc-123");
+
+        let mut missing = HeaderMap::new();
+        let id = ensure_request_id(&mut missing);
+        assert!(Uuid::parse_str(id.to_str().unwrap()).is_ok());
+        assert_eq!(missing[REQUEST_ID_HEADER], id);
+    }
+}
@@ -0,0 +1,211 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use pin_project_lite::pin_project;
+use tower::BoxError;
+
+use crate::prelude::*;
+
+pub const STATUS_HEADER: &str = "grpc-status";
+pub const MESSAGE_HEADER: &str = "grpc-message";
+
+/// gRPC status codes Lodestone answers with itself.
+pub mod code {
+    pub const UNKNOWN: u32 = 2;
+    pub const DEADLINE_EXCEEDED: u32 = 4;
+    pub const PERMISSION_DENIED: u32 = 7;
+    pub const RESOURCE_EXHAUSTED: u32 = 8;
+    pub const UNIMPLEMENTED: u32 = 12;
+    pub const INTERNAL: u32 = 13;
+    pub const UNAVAILABLE: u32 = 14;
+    pub const UNAUTHENTICATED: u32 = 16;
+}
+
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("application/grpc"))
+        .unwrap_or(false)
+}
+
+/// The `package.Service` and method of a `/package.Service/Method` path.
+pub fn method(path: &str) -> Option<(&str, &str)> {
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+    (!service.is_empty() && !method.is_empty() && !method.contains('/'))
+        .then_some((service, method))
+}
+
+/// A failure Lodestone hit itself, as the status a gRPC client expects.
+pub fn status_for_error(error: &Error) -> u32 {
+    match error {
+        Error::Timeout(_) => code::DEADLINE_EXCEEDED,
+        Error::ServiceNotFound(_) => code::UNIMPLEMENTED,
+        Error::NoHealthyInstance(_)
+        | Error::CircuitOpen(_)
+        | Error::Upstream(_)
+        | Error::Unavailable(_) => code::UNAVAILABLE,
+        Error::TooManyConnections(_) | Error::RateLimit => code::RESOURCE_EXHAUSTED,
+        Error::Auth(_) => code::UNAUTHENTICATED,
+        Error::BadRequest(_) => code::INTERNAL,
+        _ => code::UNKNOWN,
+    }
+}
+
+/// The status for an upstream answer that is not a gRPC response, as
+/// mapped by the gRPC HTTP/2 spec.
+fn status_for_http(status: StatusCode) -> u32 {
+    match status.as_u16() {
+        400 => code::INTERNAL,
+        401 => code::UNAUTHENTICATED,
+        403 => code::PERMISSION_DENIED,
+        404 => code::UNIMPLEMENTED,
+        429 | 502 | 503 | 504 => code::UNAVAILABLE,
+        _ => code::UNKNOWN,
+    }
+}
+
+/// A trailers-only response: HTTP 200 with the status in the headers, so
+/// gRPC clients see the real cause rather than a transport error.
+pub fn trailers_only(status: u32, message: &str) -> Response {
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    headers.extend(status_headers(status, message));
+    response
+}
+
+fn status_headers(status: u32, message: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(STATUS_HEADER, status.into());
+    if let Ok(message) = HeaderValue::from_str(&encode_message(message)) {
+        headers.insert(MESSAGE_HEADER, message);
+    }
+    headers
+}
+
+pub fn error_response(error: &Error) -> Response {
+    trailers_only(status_for_error(error), &error.to_string())
+}
+
+/// Turn an upstream's plain HTTP error, e.g. a `503` from a server that
+/// is not speaking gRPC, into a gRPC status. Real gRPC responses pass
+/// through, with a stream that breaks off ending in `UNAVAILABLE`.
+pub fn normalize(response: Response) -> Response {
+    if response.status() == StatusCode::OK || response.headers().contains_key(STATUS_HEADER) {
+        return response.map(|body| Body::new(UnavailableOnError::new(body)));
+    }
+    let status = response.status();
+    let message = format!("upstream answered HTTP {}", status);
+    trailers_only(status_for_http(status), &message)
+}
+
+pin_project! {
+    /// A gRPC response body that, when the upstream fails mid-stream, ends
+    /// with `UNAVAILABLE` trailers instead of resetting the client's stream.
+    pub struct UnavailableOnError<B> {
+        #[pin]
+        inner: B,
+        failed: bool,
+    }
+}
+
+impl<B> UnavailableOnError<B> {
+    pub fn new(inner: B) -> Self {
+        Self { inner, failed: false }
+    }
+}
+
+impl<B> http_body::Body for UnavailableOnError<B>
+where
+    B: http_body::Body,
+    B::Error: Into<BoxError>,
+{
+    type Data = B::Data;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        if *this.failed {
+            return Poll::Ready(None);
+        }
+        match ready!(this.inner.poll_frame(cx)) {
+            Some(Err(e)) => {
+                *this.failed = true;
+                let e = e.into();
+                tracing::warn!("gRPC response stream broke off: {}", e);
+                let trailers = status_headers(code::UNAVAILABLE, &e.to_string());
+                Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+            }
+            frame => Poll::Ready(frame.map(|frame| frame.map_err(Into::into))),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.failed || self.inner.is_end_stream()
+    }
+
+    /// The stream may end early, so no exact length is promised.
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+/// Percent-encodes `grpc-message` as the spec asks: anything outside
+/// printable ASCII, and `%` itself.
+fn encode_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..0x7f).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body::Body as _;
+
+    async fn frame<B: http_body::Body + Unpin>(body: &mut B) -> Option<Frame<B::Data>>
+    where
+        B::Error: std::fmt::Debug,
+    {
+        futures::future::poll_fn(|cx| Pin::new(&mut *body).poll_frame(cx))
+            .await
+            .map(|frame| frame.unwrap())
+    }
+
+    #[tokio::test]
+    async fn broken_stream_ends_with_unavailable_trailers() {
+        let chunks: Vec<std::result::Result<Bytes, std::io::Error>> = vec![
+            Ok(Bytes::from_static(b"message")),
+            Err(std::io::ErrorKind::ConnectionReset.into()),
+        ];
+        let mut body = UnavailableOnError::new(Body::from_stream(futures::stream::iter(chunks)));
+
+        let data = frame(&mut body).await.unwrap().into_data().unwrap();
+        assert_eq!(data, "message");
+        let trailers = frame(&mut body).await.unwrap().into_trailers().unwrap();
+        assert_eq!(trailers[STATUS_HEADER], "14");
+        assert!(trailers.contains_key(MESSAGE_HEADER));
+        assert!(body.is_end_stream());
+        assert!(frame(&mut body).await.is_none());
+    }
+}
@@ -0,0 +1,204 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{SniListenerConfig, TcpListenerConfig, TimeoutConfig};
+use crate::health::{Outcome, OutlierDetector};
+use crate::prelude::*;
+use crate::router::{CircuitBreakers, LoadBalancer, RequestAttributes, Selection};
+
+use super::{sni, upgrade};
+
+/// Instances tried for one connection before giving up on it.
+const MAX_CONNECT_ATTEMPTS: usize = 3;
+
+/// Longest wait for a client's TLS ClientHello.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause after a failed accept, such as when out of file descriptors,
+/// before trying again.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Layer 4 proxying for services that don't speak HTTP, such as
+/// databases. Each accepted connection is balanced to a healthy instance
+/// with the same load balancer, breakers and outlier detection as HTTP
+/// traffic, then tunnelled byte for byte.
+#[derive(Clone)]
+pub struct L4Proxy {
+    balancer: LoadBalancer,
+    outlier_detector: Arc<OutlierDetector>,
+    breakers: CircuitBreakers,
+    timeouts: TimeoutConfig,
+}
+
+impl L4Proxy {
+    pub fn new(
+        balancer: LoadBalancer,
+        outlier_detector: Arc<OutlierDetector>,
+        breakers: CircuitBreakers,
+        timeouts: TimeoutConfig,
+    ) -> Self {
+        Self {
+            balancer,
+            outlier_detector,
+            breakers,
+            timeouts,
+        }
+    }
+
+    /// Balance every connection on `listener` to the configured service.
+    pub async fn serve_tcp(self, listener: TcpListener, config: TcpListenerConfig) {
+        let config = Arc::new(config);
+        loop {
+            let Some((client, peer)) = Self::accept(&listener).await else {
+                continue;
+            };
+            let proxy = self.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                proxy
+                    .proxy(client, peer, &config.service, config.idle_timeout())
+                    .await
+            });
+        }
+    }
+
+    /// Pick each connection's service by the server name in its TLS
+    /// ClientHello. TLS is passed through untouched and terminated by
+    /// the instance.
+    pub async fn serve_sni(self, listener: TcpListener, config: SniListenerConfig) {
+        let config = Arc::new(config);
+        loop {
+            let Some((client, peer)) = Self::accept(&listener).await else {
+                continue;
+            };
+            let proxy = self.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let server_name = match sni::peek_server_name(&client, CLIENT_HELLO_TIMEOUT).await {
+                    Ok(Some(server_name)) => server_name,
+                    Ok(None) => {
+                        tracing::debug!("Closing connection from {}: no SNI", peer);
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::debug!("Closing connection from {}: {}", peer, e);
+                        return;
+                    }
+                };
+                match config.service_for(&server_name) {
+                    Some(service) => {
+                        proxy
+                            .proxy(client, peer, &service, config.idle_timeout())
+                            .await
+                    }
+                    None => tracing::debug!("No service for SNI name {}", server_name),
+                }
+            });
+        }
+    }
+
+    /// A failed accept only affects the connection being accepted, so
+    /// it is logged and the listener keeps going.
+    async fn accept(listener: &TcpListener) -> Option<(TcpStream, SocketAddr)> {
+        match listener.accept().await {
+            Ok(connection) => Some(connection),
+            Err(e) => {
+                tracing::warn!("Accepting connection failed: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                None
+            }
+        }
+    }
+
+    async fn proxy(
+        &self,
+        client: TcpStream,
+        peer: SocketAddr,
+        service: &str,
+        idle: Option<Duration>,
+    ) {
+        let (upstream, selection) = match self.connect(service, peer).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::warn!("Proxying connection from {} failed: {}", peer, e);
+                return;
+            }
+        };
+        match upgrade::tunnel(client, upstream, idle).await {
+            Ok((sent, received)) => tracing::debug!(
+                "Connection from {} to {} closed, {} bytes sent, {} received",
+                peer,
+                selection.service.id,
+                sent,
+                received
+            ),
+            Err(e) => tracing::debug!(
+                "Connection from {} to {} ended: {}",
+                peer,
+                selection.service.id,
+                e
+            ),
+        }
+    }
+
+    /// Connect to an instance of `service`, moving on to another one when
+    /// a connection fails or its breaker is open. The selection is held
+    /// for the life of the connection, so least-request strategies
+    /// balance open connections.
+    async fn connect(&self, service: &str, peer: SocketAddr) -> Result<(TcpStream, Selection)> {
+        let headers = HeaderMap::new();
+        let attributes = RequestAttributes {
+            headers: &headers,
+            path: "",
+            client: Some(peer.ip()),
+        };
+        let connect_timeout = self.timeouts.policy_for(service).connect();
+        let mut tried = Vec::new();
+        let mut last_error = None;
+
+        while tried.len() < MAX_CONNECT_ATTEMPTS {
+            let selection = match self
+                .balancer
+                .get_service(service, None, &attributes, &tried)
+            {
+                Ok(selection) => selection,
+                // Nothing left to try; report why the last attempt failed.
+                Err(e) => return Err(last_error.unwrap_or(e)),
+            };
+            let instance = &selection.service;
+            tried.push(instance.id.clone());
+
+            let Some(permit) = self.breakers.get(instance).acquire() else {
+                last_error = Some(Error::CircuitOpen(service.to_string()));
+                continue;
+            };
+            let address = format!("{}:{}", instance.address, instance.port);
+            match tokio::time::timeout(connect_timeout, TcpStream::connect(&address)).await {
+                Ok(Ok(upstream)) => {
+                    permit.record(false);
+                    self.outlier_detector.record(instance, Outcome::Success);
+                    return Ok((upstream, selection));
+                }
+                Ok(Err(e)) => {
+                    tracing::debug!("Connect to {} failed: {}", address, e);
+                    last_error = Some(Error::Upstream(format!(
+                        "connect to {} failed: {}",
+                        address, e
+                    )));
+                }
+                Err(_) => {
+                    tracing::debug!("Connect to {} timed out", address);
+                    last_error = Some(Error::Timeout(service.to_string()));
+                }
+            }
+            permit.record(true);
+            self.outlier_detector
+                .record(instance, Outcome::ConnectError);
+        }
+        Err(last_error.unwrap_or_else(|| Error::NoHealthyInstance(service.to_string())))
+    }
+}
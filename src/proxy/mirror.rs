@@ -0,0 +1,10 @@
+use rand::Rng;
+
+use super::route_table::Mirror;
+
+/// Set on every shadow copy so upstreams can tell it from real traffic.
+pub const MIRROR_HEADER: &str = "x-lodestone-mirror";
+
+pub fn sampled(mirror: &Mirror) -> bool {
+    rand::thread_rng().gen::<f64>() * 100.0 < mirror.percent
+}
@@ -0,0 +1,669 @@
+mod client;
+mod forwarding;
+mod grpc;
+mod l4;
+mod mirror;
+mod pool;
+mod response_cache;
+mod retry;
+mod route_table;
+mod slots;
+mod sni;
+mod template;
+mod upgrade;
+mod timeout;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request, State},
+    http::{header, request::Parts, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router as AxumRouter,
+};
+use hyper_util::rt::TokioIo;
+use tokio::time::Instant;
+use tower::timeout::error::Elapsed;
+use tower::timeout::TimeoutLayer;
+use tower::{service_fn, ServiceBuilder, ServiceExt};
+
+use crate::config::{RetryConfig, RetryPolicy, Settings, TimeoutConfig, TimeoutPolicy};
+use crate::health::{Outcome, OutlierDetector};
+use crate::prelude::*;
+use crate::router::{CircuitBreakerLayer, CircuitBreakers, CircuitOpen, LoadBalancer, RequestAttributes};
+use crate::service::{Service, Subset};
+
+pub use client::{UpstreamClient, UpstreamError};
+pub use l4::L4Proxy;
+pub use pool::PoolStats;
+pub use retry::RetryBudgets;
+pub use route_table::{read_routes_file, Route, RouteTable};
+use mirror::MIRROR_HEADER;
+use response_cache::{Cached, RouteStore};
+use route_table::{CachePolicy, Mirror};
+use slots::RouteSlots;
+use timeout::IdleTimeout;
+
+/// Instance metadata key whose value replaces the stripped routing prefix,
+/// e.g. `/api` turns `/svc/orders/v1/list` into `/api/v1/list`.
+pub const REWRITE_PREFIX_KEY: &str = "lodestone.rewrite_prefix";
+
+/// The service a request was mapped to, and the path to send upstream:
+/// what is left once the routing prefix has been stripped, or the path
+/// rewritten by the matching route. A split route also fixes the subset,
+/// so retries stay on the same backend.
+#[derive(Clone)]
+struct Target {
+    service: String,
+    subset: Option<Subset>,
+    path: String,
+    route: Option<Arc<Route>>,
+}
+
+/// How one attempt at an upstream went, and on which instance.
+enum Attempt {
+    Response(Response, String),
+    /// Nothing reached the upstream: the connection failed or the
+    /// instance's breaker was open.
+    NotSent(Error, String),
+    /// The request may have reached the upstream before failing.
+    Failed(Error, String),
+}
+
+/// Data plane: forwards requests to healthy instances of discovered
+/// services, chosen by the `LoadBalancer`.
+///
+/// Requests are mapped by the first matching route in the `RouteTable`,
+/// then by path prefix (`/svc/{name}/...`, with the prefix stripped) or by
+/// the first label of the `Host` header.
+pub struct Proxy {
+    routes: RouteTable,
+    balancer: LoadBalancer,
+    outlier_detector: Arc<OutlierDetector>,
+    breakers: CircuitBreakers,
+    client: UpstreamClient,
+    /// Shadow copies in flight per route; over the cap, copies are
+    /// dropped so a slow shadow service never holds up the primary path.
+    mirrors: RouteSlots,
+    upgrades: RouteSlots,
+    retry: RetryConfig,
+    retry_budgets: RetryBudgets,
+    timeouts: TimeoutConfig,
+    path_prefix: String,
+}
+
+impl Proxy {
+    /// The data-plane router, with the proxy as its shared state.
+    pub fn router(
+        routes: RouteTable,
+        client: UpstreamClient,
+        balancer: LoadBalancer,
+        outlier_detector: Arc<OutlierDetector>,
+        breakers: CircuitBreakers,
+        settings: &Settings,
+    ) -> AxumRouter {
+        let shared_state = Arc::new(Self {
+            routes,
+            balancer,
+            outlier_detector,
+            breakers,
+            client,
+            mirrors: RouteSlots::default(),
+            upgrades: RouteSlots::default(),
+            retry_budgets: RetryBudgets::new(&settings.retry),
+            retry: settings.retry.clone(),
+            timeouts: settings.timeouts.clone(),
+            path_prefix: settings.proxy.path_prefix.trim_end_matches('/').to_string(),
+        });
+
+        AxumRouter::new()
+            .fallback(Self::handle)
+            .with_state(shared_state)
+    }
+
+    async fn handle(
+        State(state): State<Arc<Proxy>>,
+        ConnectInfo(client): ConnectInfo<SocketAddr>,
+        mut request: Request,
+    ) -> Response {
+        let grpc = grpc::is_grpc(request.headers());
+        let Some(target) = state.resolve(&request) else {
+            let error = Error::ServiceNotFound(request.uri().path().to_string());
+            return if grpc { grpc::error_response(&error) } else { error.into_response() };
+        };
+        let upgrade = upgrade::is_upgrade(request.headers());
+        let uri = request.uri().clone();
+        let headers = request.headers_mut();
+        forwarding::strip_hop_by_hop(headers, upgrade);
+        forwarding::add_forwarded(headers, &uri, client.ip());
+        let request_id = forwarding::ensure_request_id(headers);
+
+        let cache = target.route.as_ref().and_then(|route| route.cache.clone());
+        let result = match cache {
+            _ if upgrade => state.upgrade(client, &target, request).await,
+            Some(policy) => state.cached(client, target, &policy, request).await,
+            None => state.forward(client, &target, request).await,
+        };
+        let result = result.map(|mut response| {
+            let switching = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+            forwarding::strip_hop_by_hop(response.headers_mut(), switching);
+            response
+        });
+        let mut response = match result {
+            Ok(response) if grpc => grpc::normalize(response),
+            Ok(response) => response,
+            Err(e) if grpc => grpc::error_response(&e),
+            Err(e) => e.into_response(),
+        };
+        response.headers_mut().insert(forwarding::REQUEST_ID_HEADER, request_id);
+        response
+    }
+
+    /// Pass an `Upgrade` request, such as a WebSocket handshake, to an
+    /// instance and, once it switches protocols, tunnel the connection
+    /// until either side closes or it goes idle.
+    async fn upgrade(&self, client: SocketAddr, target: &Target, mut request: Request) -> Result<Response> {
+        let slot = match &target.route {
+            Some(route) => match route.max_connections {
+                Some(limit) => Some(
+                    self.upgrades
+                        .try_acquire(&route.name, limit)
+                        .ok_or_else(|| Error::TooManyConnections(route.name.clone()))?,
+                ),
+                None => None,
+            },
+            None => None,
+        };
+        let downstream = hyper::upgrade::on(&mut request);
+        let mut response = self.forward(client, target, request).await?;
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Ok(response);
+        }
+
+        let upstream = hyper::upgrade::on(&mut response);
+        let idle = self.timeouts_for(target).idle();
+        let service = target.service.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            let (downstream, upstream) = match tokio::try_join!(downstream, upstream) {
+                Ok(connections) => connections,
+                Err(e) => {
+                    tracing::debug!("Upgrade to {} failed: {}", service, e);
+                    return;
+                }
+            };
+            match upgrade::tunnel(TokioIo::new(downstream), TokioIo::new(upstream), Some(idle)).await {
+                Ok((sent, received)) => tracing::debug!(
+                    "Upgraded connection to {} closed, {} bytes sent, {} received",
+                    service,
+                    sent,
+                    received
+                ),
+                Err(e) => tracing::debug!("Upgraded connection to {} ended: {}", service, e),
+            }
+        });
+        Ok(response)
+    }
+
+    /// Answer from the route's response cache where possible. Stale
+    /// entries within `stale-while-revalidate` are served while a
+    /// background request refreshes them. A successful unsafe request,
+    /// such as a POST, drops the cached copies of its URL.
+    async fn cached(
+        self: &Arc<Self>,
+        client: SocketAddr,
+        target: Target,
+        policy: &CachePolicy,
+        request: Request,
+    ) -> Result<Response> {
+        let (parts, body) = request.into_parts();
+        let Some(route) = &target.route else {
+            return self.forward(client, &target, Request::from_parts(parts, body)).await;
+        };
+        if response_cache::invalidates(&parts.method) {
+            let primary = response_cache::primary_key(&parts);
+            let response = self.forward(client, &target, Request::from_parts(parts, body)).await?;
+            if response.status().is_success() || response.status().is_redirection() {
+                self.routes.responses().invalidate(&route.name, &primary).await;
+            }
+            return Ok(response);
+        }
+        if !response_cache::is_cacheable_request(&parts, &body) {
+            return self.forward(client, &target, Request::from_parts(parts, body)).await;
+        }
+
+        let store = self.routes.responses().store_for(&route.name, policy);
+        let primary = response_cache::primary_key(&parts);
+        let (key, entry) = store.lookup(&primary, &parts.headers).await;
+        let entry = entry.filter(|_| !response_cache::wants_fresh(&parts));
+
+        if let Some(entry) = &entry {
+            if entry.is_fresh() {
+                return Ok(entry.respond(&parts.headers, "HIT"));
+            }
+            if entry.is_servable_stale() {
+                if store.start_revalidation(&key) {
+                    let proxy = self.clone();
+                    let (store, entry, request) = (store.clone(), entry.clone(), parts.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = proxy
+                            .fetch(client, &target, &store, &primary, request, Some(entry))
+                            .await
+                        {
+                            tracing::debug!("Revalidating {} failed: {}", primary, e);
+                        }
+                        store.finish_revalidation(&key);
+                    });
+                }
+                return Ok(entry.respond(&parts.headers, "STALE"));
+            }
+        }
+        self.fetch(client, &target, &store, &primary, parts, entry).await
+    }
+
+    /// Get a response from the upstream for the cache, revalidating
+    /// `entry` with its ETag if there is one.
+    async fn fetch(
+        &self,
+        client: SocketAddr,
+        target: &Target,
+        store: &RouteStore,
+        primary: &str,
+        parts: Parts,
+        entry: Option<Arc<Cached>>,
+    ) -> Result<Response> {
+        let mut request = Request::from_parts(parts.clone(), Body::empty());
+        if let Some(etag) = entry.as_ref().and_then(|entry| entry.etag()) {
+            request.headers_mut().insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        let authorized = parts.headers.contains_key(header::AUTHORIZATION);
+        let response = self.forward(client, target, request).await?;
+
+        if let (StatusCode::NOT_MODIFIED, Some(entry)) = (response.status(), &entry) {
+            let response = match entry.refreshed(response.headers(), authorized) {
+                Some(refreshed) => store.insert(primary, &parts.headers, refreshed).await,
+                None => entry.clone(),
+            };
+            return Ok(response.respond(&parts.headers, "REVALIDATED"));
+        }
+
+        let small = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok())
+            .is_some_and(|length| length <= store.max_entry_bytes);
+        if !small {
+            return Ok(Self::mark_miss(response));
+        }
+        let (response_parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, store.max_entry_bytes)
+            .await
+            .map_err(|e| Error::Upstream(e.to_string()))?;
+        match Cached::new(response_parts.status, response_parts.headers.clone(), body.clone(), authorized) {
+            Some(cached) => {
+                let cached = store.insert(primary, &parts.headers, cached).await;
+                Ok(cached.respond(&parts.headers, "MISS"))
+            }
+            None => Ok(Self::mark_miss(Response::from_parts(response_parts, Body::from(body)))),
+        }
+    }
+
+    fn mark_miss(mut response: Response) -> Response {
+        response.headers_mut().insert(
+            response_cache::CACHE_STATUS_HEADER,
+            HeaderValue::from_static("MISS"),
+        );
+        response
+    }
+
+    /// The route's retry policy if it sets one, otherwise the service's.
+    fn retry_for<'a>(&'a self, target: &'a Target) -> &'a RetryPolicy {
+        target
+            .route
+            .as_ref()
+            .and_then(|route| route.retry.as_ref())
+            .unwrap_or_else(|| self.retry.policy_for(&target.service))
+    }
+
+    /// The route's timeouts if it sets them, otherwise the service's.
+    fn timeouts_for<'a>(&'a self, target: &'a Target) -> &'a TimeoutPolicy {
+        target
+            .route
+            .as_ref()
+            .and_then(|route| route.timeouts.as_ref())
+            .unwrap_or_else(|| self.timeouts.policy_for(&target.service))
+    }
+
+    async fn forward(&self, client: SocketAddr, target: &Target, request: Request) -> Result<Response> {
+        let retry_budget = self.retry_budgets.budget_for(&target.service);
+        let _active = retry_budget.start_request();
+        let policy = self.retry_for(target);
+        let timeouts = self.timeouts_for(target);
+        let budget = timeout::incoming(request.headers())
+            .map_or(timeouts.request(), |caller| caller.min(timeouts.request()));
+        let deadline = Instant::now() + budget;
+
+        let (parts, body) = request.into_parts();
+        let retry_safe = retry::is_idempotent(&parts.method) || policy.retry_non_idempotent;
+        let mirror = target
+            .route
+            .as_ref()
+            .and_then(|route| route.mirror.as_ref())
+            .filter(|mirror| mirror::sampled(mirror));
+        // A gRPC call may be client-streaming or bidirectional and only
+        // end when the client is done, so it is streamed in one attempt.
+        let buffer = (policy.attempts > 1 || mirror.is_some()) && !grpc::is_grpc(&parts.headers);
+        let (mut stream, replay) = if buffer && retry::is_replayable(&body) {
+            let bytes = axum::body::to_bytes(body, retry::MAX_REPLAY_BODY)
+                .await
+                .map_err(|e| Error::BadRequest(e.to_string()))?;
+            (None, Some(bytes))
+        } else {
+            (Some(body), None)
+        };
+
+        match (mirror, &replay) {
+            (Some(mirror), Some(bytes)) => self.mirror(target, mirror, client, &parts, bytes.clone()),
+            (Some(_), None) => tracing::debug!("Not mirroring streamed request to {}", target.service),
+            _ => {}
+        }
+
+        let mut tried = Vec::new();
+        let mut attempt = 0;
+        let mut _retry = None;
+        loop {
+            attempt += 1;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Timeout(target.service.clone()));
+            }
+
+            let body = match (&replay, stream.take()) {
+                (Some(bytes), _) => Body::from(bytes.clone()),
+                (None, Some(body)) => body,
+                (None, None) => unreachable!("streamed bodies are sent once"),
+            };
+            let mut request = Request::new(body);
+            *request.method_mut() = parts.method.clone();
+            *request.uri_mut() = parts.uri.clone();
+            *request.version_mut() = parts.version;
+            *request.headers_mut() = parts.headers.clone();
+
+            let (result, instance, retryable) = match self
+                .attempt(target, client, request, &tried, remaining, timeouts)
+                .await?
+            {
+                Attempt::Response(response, instance) => {
+                    let retryable = retry_safe && policy.retry_on_status.contains(&response.status().as_u16());
+                    (Ok(response), instance, retryable)
+                }
+                Attempt::NotSent(e, instance) => (Err(e), instance, policy.retry_on_connect_error),
+                Attempt::Failed(e, instance) => (Err(e), instance, retry_safe),
+            };
+
+            if !retryable || replay.is_none() || attempt >= policy.attempts {
+                return result;
+            }
+            let backoff = retry::backoff(policy, attempt);
+            if Instant::now() + backoff >= deadline {
+                return result;
+            }
+            let Some(reserved) = retry_budget.try_retry() else {
+                tracing::debug!("Retry budget spent, not retrying {}", target.service);
+                return result;
+            };
+
+            tracing::debug!("Retrying {} after attempt {} on {}", target.service, attempt, instance);
+            _retry = Some(reserved);
+            tried.push(instance);
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    /// Copy a request to the route's shadow service in the background.
+    /// Failures are only logged and the response is thrown away.
+    fn mirror(&self, target: &Target, mirror: &Mirror, client: SocketAddr, parts: &Parts, body: Bytes) {
+        let Some(route) = &target.route else { return };
+        let Some(slot) = self.mirrors.try_acquire(&route.name, mirror.max_in_flight) else {
+            tracing::debug!("Mirror of {} at capacity, dropping copy", route.name);
+            return;
+        };
+        let selection = match self.balancer.get_service(
+            &mirror.service,
+            Some(&mirror.subset),
+            &RequestAttributes {
+                headers: &parts.headers,
+                path: &target.path,
+                client: Some(client.ip()),
+            },
+            &[],
+        ) {
+            Ok(selection) => selection,
+            Err(e) => {
+                tracing::debug!("Not mirroring {}: {}", route.name, e);
+                return;
+            }
+        };
+
+        let timeouts = self.timeouts.policy_for(&mirror.service);
+        let mut request = Request::new(Body::from(body));
+        *request.method_mut() = parts.method.clone();
+        *request.version_mut() = parts.version;
+        *request.headers_mut() = parts.headers.clone();
+        let instance = &selection.service;
+        let Ok(uri) = Self::upstream_uri(target, instance, &parts.uri) else { return };
+        *request.uri_mut() = uri;
+        if let Ok(host) = HeaderValue::from_str(&format!("{}:{}", instance.address, instance.port)) {
+            request.headers_mut().insert(header::HOST, host);
+        }
+        request.headers_mut().insert(MIRROR_HEADER, HeaderValue::from_static("1"));
+        let request_id = parts.headers.get(forwarding::REQUEST_ID_HEADER);
+        let context = template::Context {
+            route: &route.name,
+            service: &mirror.service,
+            instance,
+            client: client.ip(),
+            request_id: request_id.and_then(|id| id.to_str().ok()),
+        };
+        route.request_headers.apply(request.headers_mut(), &context);
+        timeout::propagate(request.headers_mut(), timeouts.request());
+
+        let client = self.client.clone();
+        let (connect, limit) = (timeouts.connect(), timeouts.request());
+        tokio::spawn(async move {
+            let _slot = slot;
+            let instance = &selection.service;
+            match tokio::time::timeout(limit, client.send(instance, request, connect)).await {
+                Ok(Ok(response)) => {
+                    tracing::debug!("Mirror {} answered {}", instance.id, response.status())
+                }
+                Ok(Err(e)) => tracing::debug!("Mirroring to {} failed: {}", instance.id, e),
+                Err(_) => tracing::debug!("Mirroring to {} timed out", instance.id),
+            }
+        });
+    }
+
+    /// Send one attempt to an instance not in `tried`, where possible,
+    /// giving up after `remaining`.
+    async fn attempt(
+        &self,
+        target: &Target,
+        client: SocketAddr,
+        mut request: Request,
+        tried: &[String],
+        remaining: Duration,
+        timeouts: &TimeoutPolicy,
+    ) -> Result<Attempt> {
+        // Held until the response is returned so least-request strategies
+        // see this request as in flight.
+        let selection = self.balancer.get_service(
+            &target.service,
+            target.subset.as_ref(),
+            &RequestAttributes {
+                headers: request.headers(),
+                path: &target.path,
+                client: Some(client.ip()),
+            },
+            tried,
+        )?;
+        let instance = &selection.service;
+
+        *request.uri_mut() = Self::upstream_uri(target, instance, request.uri())?;
+        let authority = format!("{}:{}", instance.address, instance.port);
+        let authority = target
+            .route
+            .as_ref()
+            .and_then(|route| route.host())
+            .unwrap_or(&authority);
+        let host =
+            HeaderValue::from_str(authority).map_err(|e| Error::BadRequest(e.to_string()))?;
+        request.headers_mut().insert(header::HOST, host);
+        timeout::propagate(request.headers_mut(), remaining);
+        let request_id = request.headers().get(forwarding::REQUEST_ID_HEADER).cloned();
+        let context = template::Context {
+            route: target.route.as_ref().map_or("", |route| route.name.as_str()),
+            service: &target.service,
+            instance,
+            client: client.ip(),
+            request_id: request_id.as_ref().and_then(|id| id.to_str().ok()),
+        };
+        if let Some(route) = &target.route {
+            route.request_headers.apply(request.headers_mut(), &context);
+        }
+
+        let client = self.client.clone();
+        let upstream = instance.clone();
+        let connect_timeout = timeouts.connect();
+        let send = service_fn(move |request| {
+            let client = client.clone();
+            let upstream = upstream.clone();
+            async move { client.send(&upstream, request, connect_timeout).await }
+        });
+        // The breaker sits outside the timeout so timeouts count against it.
+        let service = ServiceBuilder::new()
+            .layer(CircuitBreakerLayer::new(self.breakers.get(instance)))
+            .layer(TimeoutLayer::new(remaining))
+            .service(send);
+
+        match service.oneshot(request).await {
+            Ok(response) => {
+                let outcome = if response.status().is_server_error() {
+                    Outcome::ServerError
+                } else {
+                    Outcome::Success
+                };
+                self.outlier_detector.record(instance, outcome);
+                let idle = timeouts.idle();
+                let mut response = response.map(|body| Body::new(IdleTimeout::new(body, idle)));
+                if let Some(cookie) = &selection.set_cookie {
+                    if let Ok(value) = HeaderValue::from_str(cookie) {
+                        response.headers_mut().append(header::SET_COOKIE, value);
+                    }
+                }
+                if let Some(route) = &target.route {
+                    route.response_headers.apply(response.headers_mut(), &context);
+                }
+                Ok(Attempt::Response(response, instance.id.clone()))
+            }
+            Err(e) if e.is::<CircuitOpen>() => Ok(Attempt::NotSent(
+                Error::CircuitOpen(target.service.clone()),
+                instance.id.clone(),
+            )),
+            Err(e) if e.is::<Elapsed>() => {
+                self.outlier_detector.record(instance, Outcome::ServerError);
+                Ok(Attempt::Failed(
+                    Error::Timeout(target.service.clone()),
+                    instance.id.clone(),
+                ))
+            }
+            Err(e) => {
+                tracing::warn!("Proxying to {} failed: {}", target.service, e);
+                let error = Error::Upstream(e.to_string());
+                match e.downcast_ref::<UpstreamError>() {
+                    Some(UpstreamError::Connect(..)) => {
+                        self.outlier_detector.record(instance, Outcome::ConnectError);
+                        Ok(Attempt::NotSent(error, instance.id.clone()))
+                    }
+                    // Busy rather than broken: try another instance, but
+                    // don't count it as a failure.
+                    Some(UpstreamError::PoolExhausted(..)) => {
+                        Ok(Attempt::NotSent(error, instance.id.clone()))
+                    }
+                    _ => {
+                        self.outlier_detector.record(instance, Outcome::ServerError);
+                        Ok(Attempt::Failed(error, instance.id.clone()))
+                    }
+                }
+            }
+        }
+    }
+
+    fn resolve(&self, request: &Request) -> Option<Target> {
+        let path = request.uri().path();
+        if let Some(route) = self.routes.find(request) {
+            let backend = route.pick_backend(request.headers());
+            return Some(Target {
+                service: backend
+                    .and_then(|backend| backend.service.clone())
+                    .unwrap_or_else(|| route.service.clone()),
+                subset: backend.map(|backend| backend.subset.clone()),
+                path: route.rewrite_path(path),
+                route: Some(route.clone()),
+            });
+        }
+
+        if let Some(rest) = path
+            .strip_prefix(self.path_prefix.as_str())
+            .and_then(|rest| rest.strip_prefix('/'))
+        {
+            let (service, remainder) = match rest.find('/') {
+                Some(idx) => (&rest[..idx], &rest[idx..]),
+                None => (rest, "/"),
+            };
+            if !service.is_empty() {
+                return Some(Target {
+                    service: service.to_string(),
+                    subset: None,
+                    path: remainder.to_string(),
+                    route: None,
+                });
+            }
+        }
+
+        let host = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| request.uri().host())?;
+        let service = host.split(':').next()?.split('.').next()?;
+        (!service.is_empty()).then(|| Target {
+            service: service.to_string(),
+            subset: None,
+            path: path.to_string(),
+            route: None,
+        })
+    }
+
+    /// Routes rewrite the path themselves, so the instance's rewrite
+    /// prefix only applies to requests mapped by prefix or host.
+    fn upstream_uri(target: &Target, instance: &Service, original: &Uri) -> Result<Uri> {
+        let path = match instance.metadata.get(REWRITE_PREFIX_KEY) {
+            Some(prefix) if target.route.is_none() => {
+                format!("{}{}", prefix.trim_end_matches('/'), target.path)
+            }
+            _ => target.path.clone(),
+        };
+        let path_and_query = match original.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+
+        path_and_query
+            .parse()
+            .map_err(|e: axum::http::uri::InvalidUri| Error::BadRequest(e.to_string()))
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::Body;
+use dashmap::DashMap;
+use hyper::client::conn::{http1, http2};
+use serde::Serialize;
+use tokio::sync::{broadcast::error::RecvError, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::config::{ConnectionPoolConfig, PoolPolicy};
+use crate::discovery::{RegistryEvent, ServiceRegistry};
+use crate::service::Service;
+
+/// How often idle connections past their timeout are closed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Idle {
+    sender: http1::SendRequest<Body>,
+    since: Instant,
+}
+
+/// Connections to one instance. Each open connection holds a permit of
+/// `connections` until it closes.
+pub struct InstancePool {
+    service: String,
+    authority: String,
+    policy: PoolPolicy,
+    connections: Arc<Semaphore>,
+    idle: Mutex<VecDeque<Idle>>,
+    /// The HTTP/2 connection every HTTP/2 request to the instance shares.
+    /// Only held to read or swap the sender, never while connecting.
+    pub http2: Mutex<Option<http2::SendRequest<Body>>>,
+    opened: AtomicU64,
+    reused: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+    pub instance: String,
+    pub service: String,
+    pub address: String,
+    pub open: usize,
+    pub idle: usize,
+    pub http2: bool,
+    pub opened: u64,
+    pub reused: u64,
+}
+
+impl InstancePool {
+    fn new(instance: &Service, authority: String, policy: PoolPolicy) -> Self {
+        Self {
+            service: instance.name.clone(),
+            authority,
+            connections: Arc::new(Semaphore::new(policy.max_connections)),
+            policy,
+            idle: Mutex::new(VecDeque::new()),
+            http2: Mutex::new(None),
+            opened: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+        }
+    }
+
+    /// Room for one more connection, waiting up to `wait` for one to
+    /// close. `None` if the instance stays at `max_connections`.
+    pub async fn permit(&self, wait: Duration) -> Option<OwnedSemaphorePermit> {
+        let permit = self.connections.clone().acquire_owned();
+        let permit = tokio::time::timeout(wait, permit).await.ok()?.ok()?;
+        self.opened.fetch_add(1, Ordering::Relaxed);
+        Some(permit)
+    }
+
+    /// The most recently used idle HTTP/1.1 connection that is still open.
+    pub fn take_idle(&self) -> Option<http1::SendRequest<Body>> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(entry) = idle.pop_back() {
+            if entry.since.elapsed() < self.policy.idle_timeout() && entry.sender.is_ready() {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                return Some(entry.sender);
+            }
+        }
+        None
+    }
+
+    /// Keep a connection whose last response is done for reuse, unless
+    /// `max_idle` are already waiting, in which case it is closed.
+    pub fn put_idle(&self, sender: http1::SendRequest<Body>) {
+        let mut idle = self.idle.lock().unwrap();
+        if sender.is_ready() && idle.len() < self.policy.max_idle {
+            idle.push_back(Idle {
+                sender,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    pub fn count_reuse(&self) {
+        self.reused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn sweep(&self) {
+        let timeout = self.policy.idle_timeout();
+        self.idle
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.since.elapsed() < timeout && !entry.sender.is_closed());
+    }
+
+    fn stats(&self, id: &str) -> PoolStats {
+        let http2 = self
+            .http2
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|sender| !sender.is_closed());
+        PoolStats {
+            instance: id.to_string(),
+            service: self.service.clone(),
+            address: self.authority.clone(),
+            open: self.policy.max_connections - self.connections.available_permits(),
+            idle: self.idle.lock().unwrap().len(),
+            http2,
+            opened: self.opened.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Upstream connection pools by instance id. A pool goes away when its
+/// instance is deregistered or moves; connections still serving a
+/// request finish first and are then closed.
+#[derive(Clone, Default)]
+pub struct ConnectionPools {
+    config: ConnectionPoolConfig,
+    pools: Arc<DashMap<String, Arc<InstancePool>>>,
+}
+
+impl ConnectionPools {
+    pub fn new(config: ConnectionPoolConfig) -> Self {
+        Self {
+            config,
+            pools: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn get(&self, instance: &Service) -> Arc<InstancePool> {
+        let authority = format!("{}:{}", instance.address, instance.port);
+        if let Some(pool) = self.pools.get(&instance.id) {
+            if pool.authority == authority {
+                return pool.clone();
+            }
+        }
+        let policy = self.config.policy_for(&instance.name).clone();
+        let pool = Arc::new(InstancePool::new(instance, authority, policy));
+        self.pools.insert(instance.id.clone(), pool.clone());
+        pool
+    }
+
+    /// Drop pools of instances that are gone, and close connections idle
+    /// for too long.
+    pub async fn sync(self, registry: ServiceRegistry) {
+        // Subscribe before the initial load so nothing slips in between.
+        let mut events = registry.subscribe();
+        self.resync(&registry).await;
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => self.apply(&event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Connection pools missed {} registry events, resyncing", skipped);
+                        self.resync(&registry).await;
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = interval.tick() => {
+                    for pool in self.pools.iter() {
+                        pool.sweep();
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply(&self, event: &RegistryEvent) {
+        match event {
+            RegistryEvent::Deregistered { id, .. } => {
+                self.pools.remove(id);
+            }
+            RegistryEvent::Updated { service } => {
+                let authority = format!("{}:{}", service.address, service.port);
+                self.pools
+                    .remove_if(&service.id, |_, pool| pool.authority != authority);
+            }
+            _ => {}
+        }
+    }
+
+    async fn resync(&self, registry: &ServiceRegistry) {
+        match registry.list_services().await {
+            Ok(services) => self
+                .pools
+                .retain(|id, _| services.iter().any(|service| &service.id == id)),
+            Err(e) => tracing::error!("Failed to load services for connection pools: {}", e),
+        }
+    }
+
+    pub fn stats(&self) -> Vec<PoolStats> {
+        let mut stats: Vec<PoolStats> = self
+            .pools
+            .iter()
+            .map(|entry| entry.value().stats(entry.key()))
+            .collect();
+        stats.sort_by(|a, b| a.service.cmp(&b.service).then(a.instance.cmp(&b.instance)));
+        stats
+    }
+}
@@ -0,0 +1,480 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::Response,
+};
+use dashmap::DashMap;
+use moka::future::Cache;
+use tokio::time::Instant;
+
+use super::route_table::CachePolicy;
+
+/// Tells the client how the response cache handled a request: `HIT`,
+/// `STALE`, `REVALIDATED` or `MISS`.
+pub const CACHE_STATUS_HEADER: &str = "x-lodestone-cache";
+
+/// Vary lists kept per route, one per cached URL.
+const MAX_VARY_ENTRIES: u64 = 10_000;
+
+/// Response headers that describe the connection rather than the
+/// resource, and are never stored.
+const UNSTORED_HEADERS: [HeaderName; 4] = [
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::TE,
+    header::TRAILER,
+];
+
+/// Headers carried on a `304 Not Modified` answered from the cache.
+const NOT_MODIFIED_HEADERS: [HeaderName; 5] = [
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::ETAG,
+    header::EXPIRES,
+    header::VARY,
+];
+
+/// The `Cache-Control` directives that decide what gets cached.
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        let values = headers
+            .get_all(header::CACHE_CONTROL)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok());
+        for directive in values.flat_map(|value| value.split(',')) {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = argument.and_then(|argument| argument.parse().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "max-age" => directives.max_age = seconds,
+                "s-maxage" => directives.s_maxage = seconds,
+                "stale-while-revalidate" => directives.stale_while_revalidate = seconds,
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+/// Whether a request may be answered from, and fill, the cache. Only
+/// bodiless GETs are; the body's framing tells, since `Transfer-Encoding`
+/// is stripped as hop-by-hop before the request gets here.
+pub fn is_cacheable_request(parts: &Parts, body: &Body) -> bool {
+    let has_body = body.size_hint().exact() != Some(0);
+    parts.method == Method::GET && !has_body && !CacheControl::parse(&parts.headers).no_store
+}
+
+/// Whether the client asked for a response fresh from the upstream.
+pub fn wants_fresh(parts: &Parts) -> bool {
+    CacheControl::parse(&parts.headers).no_cache
+        || parts
+            .headers
+            .get(header::PRAGMA)
+            .is_some_and(|pragma| pragma == "no-cache")
+}
+
+/// Whether a successful response to `method` makes cached copies of the
+/// URL out of date: every method not defined as safe (RFC 9111, section 4.4).
+pub fn invalidates(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+}
+
+/// The cached URL: host, path and query.
+pub fn primary_key(parts: &Parts) -> String {
+    let host = parts
+        .headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| parts.uri.host())
+        .unwrap_or_default();
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    format!("{}{}", host, path)
+}
+
+fn variant_key(primary: &str, vary: &[HeaderName], headers: &HeaderMap) -> String {
+    let mut key = primary.to_string();
+    for name in vary {
+        key.push('\n');
+        key.push_str(name.as_str());
+        key.push(':');
+        for value in headers.get_all(name) {
+            key.push_str(value.to_str().unwrap_or_default());
+            key.push(',');
+        }
+    }
+    key
+}
+
+/// Whether `If-None-Match` in `headers` lists `etag`, compared weakly.
+fn etag_matches(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let Ok(etag) = etag.to_str() else { return false };
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || strip(tag) == strip(etag))
+}
+
+/// A stored response and how long it may be served.
+#[derive(Debug)]
+pub struct Cached {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    fresh_for: Duration,
+    stale_for: Duration,
+}
+
+impl Cached {
+    /// Store a response whose headers allow it, or `None`. Responses to
+    /// authorized requests are only shared when explicitly `public`.
+    pub fn new(status: StatusCode, mut headers: HeaderMap, body: Bytes, authorized: bool) -> Option<Self> {
+        let cacheable_status = matches!(status.as_u16(), 200 | 203 | 301 | 404 | 410);
+        if !cacheable_status || headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        let (fresh_for, stale_for) = Self::lifetime(&headers, authorized)?;
+        for name in UNSTORED_HEADERS {
+            headers.remove(name);
+        }
+        headers.remove(CACHE_STATUS_HEADER);
+        Some(Self {
+            status,
+            headers,
+            body,
+            stored: Instant::now(),
+            fresh_for,
+            stale_for,
+        })
+    }
+
+    fn lifetime(headers: &HeaderMap, authorized: bool) -> Option<(Duration, Duration)> {
+        let directives = CacheControl::parse(headers);
+        if directives.no_store || directives.private {
+            return None;
+        }
+        if authorized && !directives.public && directives.s_maxage.is_none() {
+            return None;
+        }
+        // `no-cache` responses are kept, but revalidated before each use.
+        let fresh_for = match directives.s_maxage.or(directives.max_age) {
+            _ if directives.no_cache => Duration::ZERO,
+            Some(max_age) => Duration::from_secs(max_age),
+            None => return None,
+        };
+        let stale_for = Duration::from_secs(directives.stale_while_revalidate.unwrap_or(0));
+        if fresh_for.is_zero() && stale_for.is_zero() && !headers.contains_key(header::ETAG) {
+            return None;
+        }
+        Some((fresh_for, stale_for))
+    }
+
+    /// The stored response updated by a `304 Not Modified` from the
+    /// upstream, fresh again.
+    pub fn refreshed(&self, headers: &HeaderMap, authorized: bool) -> Option<Self> {
+        let mut merged = self.headers.clone();
+        for name in NOT_MODIFIED_HEADERS {
+            if let Some(value) = headers.get(&name) {
+                merged.insert(name, value.clone());
+            }
+        }
+        Self::new(self.status, merged, self.body.clone(), authorized)
+    }
+
+    pub fn etag(&self) -> Option<&HeaderValue> {
+        self.headers.get(header::ETAG)
+    }
+
+    fn vary(&self) -> Option<Vec<HeaderName>> {
+        let mut names = Vec::new();
+        for value in self.headers.get_all(header::VARY) {
+            for name in value.to_str().ok()?.split(',') {
+                let name = name.trim();
+                if name == "*" {
+                    return None;
+                }
+                names.push(HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes()).ok()?);
+            }
+        }
+        names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        Some(names)
+    }
+
+    fn age(&self) -> Duration {
+        self.stored.elapsed()
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.age() < self.fresh_for
+    }
+
+    /// Stale, but still within `stale-while-revalidate`.
+    pub fn is_servable_stale(&self) -> bool {
+        self.age() < self.fresh_for + self.stale_for
+    }
+
+    fn weight(&self, key: &str) -> u32 {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        (key.len() + headers + self.body.len()).try_into().unwrap_or(u32::MAX)
+    }
+
+    /// Answer a request from this entry: `304` when the client already
+    /// has it, the stored response otherwise.
+    pub fn respond(&self, request: &HeaderMap, cache_status: &'static str) -> Response {
+        let not_modified = self.etag().is_some_and(|etag| etag_matches(request, etag));
+        let mut response = if not_modified {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            for name in NOT_MODIFIED_HEADERS {
+                for value in self.headers.get_all(&name) {
+                    response.headers_mut().append(name.clone(), value.clone());
+                }
+            }
+            response
+        } else {
+            let mut response = Response::new(Body::from(self.body.clone()));
+            *response.status_mut() = self.status;
+            *response.headers_mut() = self.headers.clone();
+            response
+        };
+        response.headers_mut().insert(header::AGE, self.age().as_secs().into());
+        response
+            .headers_mut()
+            .insert(CACHE_STATUS_HEADER, HeaderValue::from_static(cache_status));
+        response
+    }
+}
+
+/// Cached responses of one route, bounded by the route's byte budget.
+pub struct RouteStore {
+    max_bytes: u64,
+    pub max_entry_bytes: usize,
+    entries: Cache<String, Arc<Cached>>,
+    vary: Cache<String, Arc<Vec<HeaderName>>>,
+    revalidating: DashMap<String, ()>,
+}
+
+impl RouteStore {
+    fn new(policy: &CachePolicy) -> Self {
+        Self {
+            max_bytes: policy.max_bytes,
+            max_entry_bytes: policy.max_entry_bytes,
+            entries: Cache::builder()
+                .max_capacity(policy.max_bytes)
+                .weigher(|key: &String, entry: &Arc<Cached>| entry.weight(key))
+                .build(),
+            vary: Cache::new(MAX_VARY_ENTRIES),
+            revalidating: DashMap::new(),
+        }
+    }
+
+    /// The entry for a request, and the key it is stored under.
+    pub async fn lookup(&self, primary: &str, headers: &HeaderMap) -> (String, Option<Arc<Cached>>) {
+        let vary = self.vary.get(primary).await.unwrap_or_default();
+        let key = variant_key(primary, &vary, headers);
+        let entry = self.entries.get(&key).await;
+        (key, entry)
+    }
+
+    pub async fn insert(&self, primary: &str, headers: &HeaderMap, entry: Cached) -> Arc<Cached> {
+        let entry = Arc::new(entry);
+        let Some(vary) = entry.vary() else {
+            return entry;
+        };
+        let key = variant_key(primary, &vary, headers);
+        self.vary.insert(primary.to_string(), Arc::new(vary)).await;
+        self.entries.insert(key, entry.clone()).await;
+        entry
+    }
+
+    /// Drop every cached variant of `primary`.
+    pub async fn invalidate(&self, primary: &str) {
+        self.vary.invalidate(primary).await;
+        let variants = format!("{}\n", primary);
+        let keys: Vec<Arc<String>> = self
+            .entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.as_str() == primary || key.starts_with(&variants))
+            .collect();
+        for key in keys {
+            self.entries.invalidate(key.as_str()).await;
+        }
+    }
+
+    /// Claim the background revalidation of `key`; `false` if one is
+    /// already running.
+    pub fn start_revalidation(&self, key: &str) -> bool {
+        self.revalidating.insert(key.to_string(), ()).is_none()
+    }
+
+    pub fn finish_revalidation(&self, key: &str) {
+        self.revalidating.remove(key);
+    }
+}
+
+/// Response caches of every route that enables one.
+#[derive(Clone, Default)]
+pub struct ResponseCache {
+    routes: Arc<DashMap<String, Arc<RouteStore>>>,
+}
+
+impl ResponseCache {
+    /// The store for `route`, started afresh when its size limit changes.
+    pub fn store_for(&self, route: &str, policy: &CachePolicy) -> Arc<RouteStore> {
+        if let Some(store) = self.routes.get(route) {
+            if store.max_bytes == policy.max_bytes && store.max_entry_bytes == policy.max_entry_bytes {
+                return store.clone();
+            }
+        }
+        let store = Arc::new(RouteStore::new(policy));
+        self.routes.insert(route.to_string(), store.clone());
+        store
+    }
+
+    /// Drop cached copies of `primary` held for `route`, if any.
+    pub async fn invalidate(&self, route: &str, primary: &str) {
+        let store = self.routes.get(route).map(|store| store.clone());
+        if let Some(store) = store {
+            store.invalidate(primary).await;
+        }
+    }
+
+    /// Free the store of a route that was deleted or stopped caching.
+    pub fn remove(&self, route: &str) {
+        self.routes.remove(route);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_control(values: &[&'static str]) -> CacheControl {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::CACHE_CONTROL, HeaderValue::from_static(value));
+        }
+        CacheControl::parse(&headers)
+    }
+
+    #[test]
+    fn parses_directives_across_headers() {
+        let directives = cache_control(&[
+            "public, max-age=60",
+            "S-MAXAGE=\"120\", stale-while-revalidate=30",
+        ]);
+        assert!(directives.public);
+        assert_eq!(directives.max_age, Some(60));
+        assert_eq!(directives.s_maxage, Some(120));
+        assert_eq!(directives.stale_while_revalidate, Some(30));
+        assert!(!directives.no_store && !directives.no_cache && !directives.private);
+    }
+
+    #[test]
+    fn parses_flags_and_ignores_unknown_directives() {
+        let directives =
+            cache_control(&["no-store, No-Cache , private, must-revalidate, community=\"x\""]);
+        assert!(directives.no_store && directives.no_cache && directives.private);
+        assert_eq!(directives.max_age, None);
+    }
+
+    #[test]
+    fn invalid_ages_are_ignored() {
+        let directives = cache_control(&["max-age=soon, s-maxage=-1, stale-while-revalidate="]);
+        assert_eq!(directives.max_age, None);
+        assert_eq!(directives.s_maxage, None);
+        assert_eq!(directives.stale_while_revalidate, None);
+        assert!(!cache_control(&[]).no_store);
+    }
+
+    #[test]
+    fn lifetime_follows_directives() {
+        let lifetime = |values: &[&'static str], authorized| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(header::CACHE_CONTROL, HeaderValue::from_static(value));
+            }
+            Cached::lifetime(&headers, authorized)
+        };
+        assert_eq!(
+            lifetime(
+                &["max-age=60, s-maxage=10, stale-while-revalidate=5"],
+                false
+            ),
+            Some((Duration::from_secs(10), Duration::from_secs(5)))
+        );
+        assert_eq!(lifetime(&["max-age=60, private"], false), None);
+        assert_eq!(lifetime(&["no-store, max-age=60"], false), None);
+        assert_eq!(lifetime(&["max-age=60"], true), None);
+        assert_eq!(
+            lifetime(&["public, max-age=60"], true),
+            Some((Duration::from_secs(60), Duration::ZERO))
+        );
+        assert_eq!(lifetime(&[], false), None);
+    }
+
+    #[test]
+    fn only_unsafe_methods_invalidate() {
+        assert!(invalidates(&Method::POST) && invalidates(&Method::DELETE));
+        assert!(invalidates(&Method::from_bytes(b"PURGE").unwrap()));
+        assert!(!invalidates(&Method::GET) && !invalidates(&Method::HEAD));
+    }
+
+    #[tokio::test]
+    async fn invalidate_drops_every_variant_of_the_url() {
+        let store = RouteStore::new(&CachePolicy {
+            max_bytes: 1 << 20,
+            max_entry_bytes: 1 << 16,
+        });
+        let cached = |vary| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+            headers.insert(header::VARY, HeaderValue::from_static(vary));
+            Cached::new(StatusCode::OK, headers, Bytes::from_static(b"x"), false).unwrap()
+        };
+        let language = |value| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static(value));
+            headers
+        };
+        for value in ["en", "de"] {
+            store.insert("shop/cart", &language(value), cached("accept-language")).await;
+        }
+        store.insert("shop/cart/items", &language("en"), cached("accept-language")).await;
+
+        store.invalidate("shop/cart").await;
+        for value in ["en", "de"] {
+            assert!(store.lookup("shop/cart", &language(value)).await.1.is_none());
+        }
+        assert!(store.lookup("shop/cart/items", &language("en")).await.1.is_some());
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{Body, HttpBody};
+use axum::http::Method;
+use dashmap::DashMap;
+use rand::Rng;
+
+use crate::config::{RetryConfig, RetryPolicy};
+
+/// Request bodies up to this size are buffered so they can be replayed on
+/// another instance. Larger or chunked bodies are streamed and never
+/// retried.
+pub const MAX_REPLAY_BODY: usize = 64 * 1024;
+
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Whether `body` is small enough to buffer. Only a body of known length
+/// is: one sent chunked, or over HTTP/2 without `Content-Length`, may be
+/// a long-lived stream. The length comes from the body's framing rather
+/// than the headers, which lose `Transfer-Encoding` as hop-by-hop.
+pub fn is_replayable(body: &Body) -> bool {
+    body.size_hint()
+        .exact()
+        .is_some_and(|length| length <= MAX_REPLAY_BODY as u64)
+}
+
+/// Delay before retry number `retry`, counting from 1: exponential up to
+/// `backoff_max`, with full jitter so retries from many clients spread out.
+pub fn backoff(policy: &RetryPolicy, retry: u32) -> Duration {
+    let ceiling = policy
+        .backoff_base()
+        .saturating_mul(1 << retry.saturating_sub(1).min(16))
+        .min(policy.backoff_max());
+    ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Limits retries in flight to a share of active requests, so an upstream
+/// that is already failing does not get hit with a retry storm.
+#[derive(Debug, Clone)]
+pub struct RetryBudget {
+    active: Arc<AtomicUsize>,
+    retries: Arc<AtomicUsize>,
+    percent: usize,
+    min_concurrency: usize,
+}
+
+/// Keeps a request or retry counted until dropped.
+#[derive(Debug)]
+pub struct Tracked(Arc<AtomicUsize>);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RetryBudget {
+    pub fn new(percent: u8, min_concurrency: usize) -> Self {
+        Self {
+            active: Arc::new(AtomicUsize::new(0)),
+            retries: Arc::new(AtomicUsize::new(0)),
+            percent: percent as usize,
+            min_concurrency,
+        }
+    }
+
+    pub fn start_request(&self) -> Tracked {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Tracked(self.active.clone())
+    }
+
+    /// Reserve a retry, or `None` when the budget is spent.
+    pub fn try_retry(&self) -> Option<Tracked> {
+        let limit = (self.active.load(Ordering::Relaxed) * self.percent / 100).max(self.min_concurrency);
+        self.retries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |retries| {
+                (retries < limit).then_some(retries + 1)
+            })
+            .ok()?;
+        Some(Tracked(self.retries.clone()))
+    }
+}
+
+/// One `RetryBudget` per upstream service, keyed like the retry policies,
+/// so a failing service spends only its own budget.
+#[derive(Debug, Clone)]
+pub struct RetryBudgets {
+    budgets: Arc<DashMap<String, RetryBudget>>,
+    percent: u8,
+    min_concurrency: usize,
+}
+
+impl RetryBudgets {
+    pub fn new(config: &RetryConfig) -> Self {
+        Self {
+            budgets: Arc::new(DashMap::new()),
+            percent: config.budget_percent,
+            min_concurrency: config.min_retry_concurrency,
+        }
+    }
+
+    pub fn budget_for(&self, service: &str) -> RetryBudget {
+        if let Some(budget) = self.budgets.get(service) {
+            return budget.clone();
+        }
+        self.budgets
+            .entry(service.to_string())
+            .or_insert_with(|| RetryBudget::new(self.percent, self.min_concurrency))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn services_spend_their_own_budgets() {
+        let config: RetryConfig = toml::from_str("budget_percent = 20\nmin_retry_concurrency = 1").unwrap();
+        let budgets = RetryBudgets::new(&config);
+
+        let _orders = budgets.budget_for("orders").start_request();
+        let spent = budgets.budget_for("orders").try_retry();
+        assert!(spent.is_some());
+        assert!(budgets.budget_for("orders").try_retry().is_none());
+        assert!(budgets.budget_for("payments").try_retry().is_some());
+
+        drop(spent);
+        assert!(budgets.budget_for("orders").try_retry().is_some());
+    }
+}
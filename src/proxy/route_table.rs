@@ -0,0 +1,795 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use axum::{
+    extract::{Query, Request},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method},
+};
+use rand::Rng;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::config::{RetryPolicy, TimeoutPolicy};
+use crate::consensus::{self, RaftNode, StateMachine};
+use crate::prelude::*;
+use crate::service::Subset;
+use crate::store::Store;
+
+use super::response_cache::ResponseCache;
+use super::{grpc, template};
+
+const ROUTES_TREE: &str = "routes";
+/// Names of routes deleted through the API, so an unchanged routes file
+/// does not bring them back.
+const DELETED_ROUTES_TREE: &str = "deleted_routes";
+/// Each routes file entry as this node last applied it. Node-local, unlike
+/// the replicated trees above.
+const FILE_ROUTES_TREE: &str = "file_routes";
+/// Wait between attempts to apply the routes file while there is no
+/// Raft leader.
+const FILE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A regular expression that has to match the whole value.
+#[derive(Clone)]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(source: String) -> std::result::Result<Self, Self::Error> {
+        let regex = Regex::new(&format!("^(?:{})$", source))?;
+        Ok(Self { source, regex })
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.source)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Pattern::try_from(source).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathMatch {
+    Exact(String),
+    Prefix(String),
+    Regex(Pattern),
+}
+
+impl PathMatch {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatch::Exact(exact) => path == exact,
+            PathMatch::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathMatch::Regex(pattern) => pattern.regex.is_match(path),
+        }
+    }
+}
+
+/// Condition on a header or query parameter. `present = false` matches
+/// only when it is absent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueMatch {
+    Exact(String),
+    Prefix(String),
+    Regex(Pattern),
+    Present(bool),
+}
+
+impl ValueMatch {
+    fn matches(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (ValueMatch::Present(present), value) => *present == value.is_some(),
+            (_, None) => false,
+            (ValueMatch::Exact(exact), Some(value)) => value == exact,
+            (ValueMatch::Prefix(prefix), Some(value)) => value.starts_with(prefix.as_str()),
+            (ValueMatch::Regex(pattern), Some(value)) => pattern.regex.is_match(value),
+        }
+    }
+}
+
+/// A gRPC call by its `/package.Service/Method` path; without a method,
+/// every method of the service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrpcMatch {
+    pub service: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+}
+
+impl GrpcMatch {
+    fn matches(&self, request: &Request) -> bool {
+        if !grpc::is_grpc(request.headers()) {
+            return false;
+        }
+        match grpc::method(request.uri().path()) {
+            Some((service, method)) => {
+                service == self.service && self.method.as_deref().is_none_or(|m| m == method)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Every condition that is set has to hold for the route to match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RouteMatch {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathMatch>,
+    /// Any of these methods; empty matches every method.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, ValueMatch>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, ValueMatch>,
+    /// Exact host, or `*.example.com` for any subdomain. The port is
+    /// ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grpc: Option<GrpcMatch>,
+}
+
+impl RouteMatch {
+    fn matches(&self, request: &Request) -> bool {
+        if let Some(path) = &self.path {
+            if !path.matches(request.uri().path()) {
+                return false;
+            }
+        }
+        if let Some(grpc) = &self.grpc {
+            if !grpc.matches(request) {
+                return false;
+            }
+        }
+        if !self.methods.is_empty()
+            && !self
+                .methods
+                .iter()
+                .any(|method| method.eq_ignore_ascii_case(request.method().as_str()))
+        {
+            return false;
+        }
+        if let Some(host) = &self.host {
+            if !Self::host_matches(host, request) {
+                return false;
+            }
+        }
+
+        let headers = request.headers();
+        let headers_match = self.headers.iter().all(|(name, condition)| {
+            condition.matches(headers.get(name.as_str()).and_then(|value| value.to_str().ok()))
+        });
+        if !headers_match {
+            return false;
+        }
+
+        if self.query.is_empty() {
+            return true;
+        }
+        let query = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+            .map(|Query(query)| query)
+            .unwrap_or_default();
+        self.query
+            .iter()
+            .all(|(name, condition)| condition.matches(query.get(name).map(String::as_str)))
+    }
+
+    fn host_matches(expected: &str, request: &Request) -> bool {
+        let Some(host) = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| request.uri().host())
+        else {
+            return false;
+        };
+        let host = host.split(':').next().unwrap_or(host);
+        match expected.strip_prefix("*.") {
+            Some(domain) => host
+                .len()
+                .checked_sub(domain.len() + 1)
+                .map(|split| {
+                    host.as_bytes()[split] == b'.' && host[split + 1..].eq_ignore_ascii_case(domain)
+                })
+                .unwrap_or(false),
+            None => host.eq_ignore_ascii_case(expected),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegexRewrite {
+    pub pattern: Pattern,
+    /// Replacement for the whole path; `$1` and `$name` refer to groups.
+    pub substitution: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rewrite {
+    /// Replaces the part of the path matched by a `prefix` path rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<RegexRewrite>,
+    /// Host header sent upstream instead of the instance address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+/// Headers to set, overwriting existing values, and headers to drop.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HeaderRules {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub add: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+}
+
+impl HeaderRules {
+    fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+
+    fn validate(&self) -> Result<()> {
+        for name in self.add.keys().chain(&self.remove) {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::BadRequest(format!("invalid header name: {}", name)))?;
+        }
+        for value in self.add.values() {
+            HeaderValue::from_str(value)
+                .map_err(|_| Error::BadRequest(format!("invalid header value: {}", value)))?;
+            template::validate(value)?;
+        }
+        Ok(())
+    }
+
+    /// Values are templates, filled in for the instance handling the
+    /// attempt.
+    pub fn apply(&self, headers: &mut HeaderMap, context: &template::Context) {
+        for name in &self.remove {
+            headers.remove(name.as_str());
+        }
+        for (name, value) in &self.add {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(&template::render(value, context)),
+            ) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+/// One side of a traffic split: a subset of the route's service, or of
+/// another service for blue/green releases.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backend {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    #[serde(default)]
+    pub subset: Subset,
+    /// Share of the route's traffic, relative to the other backends.
+    pub weight: u32,
+}
+
+/// Fire-and-forget copies of a share of a route's requests, sent to a
+/// shadow service whose responses are discarded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mirror {
+    pub service: String,
+    #[serde(default)]
+    pub subset: Subset,
+    /// Percentage of requests copied, from 0 to 100.
+    pub percent: f64,
+    /// Copies in flight for the route before further ones are dropped.
+    #[serde(default = "default_mirror_in_flight")]
+    pub max_in_flight: usize,
+}
+
+fn default_mirror_in_flight() -> usize {
+    32
+}
+
+/// Caches the route's GET responses for as long as their
+/// `Cache-Control` allows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachePolicy {
+    /// Total size of the route's cached responses, in bytes.
+    pub max_bytes: u64,
+    /// Largest single response body cached, in bytes.
+    #[serde(default = "default_max_entry_bytes")]
+    pub max_entry_bytes: usize,
+}
+
+fn default_max_entry_bytes() -> usize {
+    1024 * 1024
+}
+
+/// Maps matching requests to a service. Routes are tried from the highest
+/// `priority` down, ties broken by name; the first match wins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Route {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(rename = "match", default)]
+    pub matches: RouteMatch,
+    pub service: String,
+    /// Weighted backends; empty sends everything to any instance of
+    /// `service`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub split: Vec<Backend>,
+    /// Request header naming a backend to use regardless of weights.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub override_header: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<Mirror>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CachePolicy>,
+    /// Replaces the service's retry policy for requests on this route.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// Replaces the service's timeout policy for requests on this route.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeouts: Option<TimeoutPolicy>,
+    /// Upgraded connections, such as WebSockets, open at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<Rewrite>,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub request_headers: HeaderRules,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub response_headers: HeaderRules,
+}
+
+impl Route {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(Error::BadRequest("route name is required".to_string()));
+        }
+        if self.service.is_empty() {
+            return Err(Error::BadRequest(format!("route {} has no service", self.name)));
+        }
+        for method in &self.matches.methods {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|_| Error::BadRequest(format!("invalid method: {}", method)))?;
+        }
+        if let Some(grpc) = &self.matches.grpc {
+            if grpc.service.is_empty() || grpc.service.contains('/') {
+                return Err(Error::BadRequest(format!("invalid gRPC service: {}", grpc.service)));
+            }
+        }
+        for name in self.matches.headers.keys().chain(&self.override_header) {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::BadRequest(format!("invalid header name: {}", name)))?;
+        }
+        for (i, backend) in self.split.iter().enumerate() {
+            if backend.name.is_empty() || self.split[..i].iter().any(|b| b.name == backend.name) {
+                return Err(Error::BadRequest(format!(
+                    "route {} needs unique, non-empty backend names",
+                    self.name
+                )));
+            }
+        }
+        if !self.split.is_empty() && self.split.iter().all(|backend| backend.weight == 0) {
+            return Err(Error::BadRequest(format!("route {} has no backend weight", self.name)));
+        }
+        if let Some(mirror) = &self.mirror {
+            if mirror.service.is_empty() || !(0.0..=100.0).contains(&mirror.percent) {
+                return Err(Error::BadRequest(format!(
+                    "route {} needs a mirror service and a percent from 0 to 100",
+                    self.name
+                )));
+            }
+        }
+        if let Some(rewrite) = &self.rewrite {
+            if rewrite.prefix.is_some() && rewrite.regex.is_some() {
+                return Err(Error::BadRequest(
+                    "rewrite takes either prefix or regex, not both".to_string(),
+                ));
+            }
+            if rewrite.prefix.is_some() && !matches!(self.matches.path, Some(PathMatch::Prefix(_))) {
+                return Err(Error::BadRequest(
+                    "prefix rewrite needs a prefix path match".to_string(),
+                ));
+            }
+            if let Some(host) = &rewrite.host {
+                HeaderValue::from_str(host)
+                    .map_err(|_| Error::BadRequest(format!("invalid rewrite host: {}", host)))?;
+            }
+        }
+        self.request_headers.validate()?;
+        self.response_headers.validate()
+    }
+
+    /// The path to send upstream.
+    pub fn rewrite_path(&self, path: &str) -> String {
+        let Some(rewrite) = &self.rewrite else {
+            return path.to_string();
+        };
+        if let (Some(replacement), Some(PathMatch::Prefix(prefix))) =
+            (&rewrite.prefix, &self.matches.path)
+        {
+            let path = format!("{}{}", replacement, &path[prefix.len().min(path.len())..]);
+            return if path.starts_with('/') { path } else { format!("/{}", path) };
+        }
+        if let Some(regex) = &rewrite.regex {
+            return regex
+                .pattern
+                .regex
+                .replace(path, regex.substitution.as_str())
+                .into_owned();
+        }
+        path.to_string()
+    }
+
+    /// The backend for a request: the one named in the override header,
+    /// or a weighted random choice.
+    pub fn pick_backend(&self, headers: &HeaderMap) -> Option<&Backend> {
+        let forced = self
+            .override_header
+            .as_deref()
+            .and_then(|name| headers.get(name))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| self.split.iter().find(|backend| backend.name == value));
+        if forced.is_some() {
+            return forced;
+        }
+
+        let total: u64 = self.split.iter().map(|backend| backend.weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0..total);
+        self.split.iter().find(|backend| {
+            let weight = backend.weight as u64;
+            if point < weight {
+                true
+            } else {
+                point -= weight;
+                false
+            }
+        })
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.rewrite.as_ref()?.host.as_deref()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RoutesFile {
+    #[serde(default)]
+    routes: Vec<Route>,
+}
+
+/// Parse and validate a routes file.
+pub fn read_routes_file(file: &Path) -> Result<Vec<Route>> {
+    let contents = std::fs::read_to_string(file)?;
+    let parsed: RoutesFile = toml::from_str(&contents)
+        .map_err(|e| Error::Config(format!("{}: {}", file.display(), e)))?;
+    for route in &parsed.routes {
+        route.validate()?;
+    }
+    Ok(parsed.routes)
+}
+
+/// A change to the route table, replicated through Raft.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum RouteCommand {
+    Put { route: Box<Route> },
+    /// Applied to the route as stored, so concurrent changes to the same
+    /// route are not lost.
+    SetWeights { name: String, weights: HashMap<String, u32> },
+    Delete { name: String },
+}
+
+impl RouteCommand {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self).map_err(|e| Error::Storage(e.to_string()))
+    }
+}
+
+/// Declarative routes evaluated by the proxy before falling back to
+/// `/svc/{name}` and Host based routing. Routes change only by applying
+/// `RouteCommand`s committed through Raft, so every node holds the same
+/// table; the routes file, if any, is proposed at startup.
+#[derive(Clone)]
+pub struct RouteTable {
+    store: Arc<Store>,
+    routes: Arc<ArcSwap<Vec<Arc<Route>>>>,
+    /// Cached responses of routes that enable a cache, dropped with the
+    /// route.
+    responses: ResponseCache,
+}
+
+impl RouteTable {
+    pub fn load(store: Arc<Store>) -> Result<Self> {
+        let table = Self {
+            store,
+            routes: Arc::new(ArcSwap::from_pointee(Vec::new())),
+            responses: ResponseCache::default(),
+        };
+        table.reload()?;
+        tracing::info!("Loaded {} routes", table.routes.load().len());
+        Ok(table)
+    }
+
+    fn reload(&self) -> Result<()> {
+        let mut routes: Vec<Arc<Route>> = self
+            .store
+            .list_in::<Route>(ROUTES_TREE)?
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+        routes.sort_by(|a, b| b.priority.cmp(&a.priority).then_with(|| a.name.cmp(&b.name)));
+        self.routes.store(Arc::new(routes));
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<Route> {
+        self.routes.load().iter().map(|route| route.as_ref().clone()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Route> {
+        self.routes
+            .load()
+            .iter()
+            .find(|route| route.name == name)
+            .map(|route| route.as_ref().clone())
+    }
+
+    /// Store a route through Raft, replacing any of the same name.
+    pub async fn put(&self, raft: &RwLock<RaftNode>, route: Route) -> Result<()> {
+        route.validate()?;
+        let command = RouteCommand::Put { route: Box::new(route) };
+        consensus::propose(raft, command.encode()?).await
+    }
+
+    /// Change backend weights of a live route, leaving others as they are.
+    pub async fn set_weights(
+        &self,
+        raft: &RwLock<RaftNode>,
+        name: &str,
+        weights: HashMap<String, u32>,
+    ) -> Result<Route> {
+        let command = RouteCommand::SetWeights { name: name.to_string(), weights };
+        consensus::propose(raft, command.encode()?).await?;
+        self.get(name)
+            .ok_or_else(|| Error::ServiceNotFound(name.to_string()))
+    }
+
+    /// Delete a route through Raft, leaving a tombstone so an unchanged
+    /// routes file does not restore it.
+    pub async fn delete(&self, raft: &RwLock<RaftNode>, name: &str) -> Result<()> {
+        let command = RouteCommand::Delete { name: name.to_string() };
+        consensus::propose(raft, command.encode()?).await
+    }
+
+    /// Propose the routes file entries that are new or changed since this
+    /// node last applied the file; they replace routes of the same name
+    /// and clear tombstones. Unchanged entries leave API changes alone.
+    pub async fn sync_file(self, raft: Arc<RwLock<RaftNode>>, routes: Vec<Route>) {
+        if let Err(e) = self.forget_removed(&routes) {
+            tracing::warn!("Failed to prune routes file records: {}", e);
+        }
+
+        for route in routes {
+            let recorded = match self.store.get_from::<Route>(FILE_ROUTES_TREE, &route.name) {
+                Ok(recorded) => recorded,
+                Err(e) => {
+                    tracing::warn!("Skipping route {} from the routes file: {}", route.name, e);
+                    continue;
+                }
+            };
+            let deleted = self.is_deleted(&route.name);
+            if recorded.is_some_and(|recorded| same_route(&recorded, &route)) {
+                if deleted {
+                    tracing::info!(
+                        "Route {} in the routes file stays deleted: it was deleted through the API",
+                        route.name
+                    );
+                }
+                continue;
+            }
+            if deleted {
+                tracing::info!(
+                    "Restoring route {} deleted through the API: the routes file changed it",
+                    route.name
+                );
+            }
+
+            loop {
+                match self.put(&raft, route.clone()).await {
+                    Ok(()) => break,
+                    Err(Error::Unavailable(e)) => {
+                        tracing::debug!("Route {} from the routes file waits: {}", route.name, e);
+                        tokio::time::sleep(FILE_RETRY_INTERVAL).await;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to apply route {} from the routes file: {}", route.name, e);
+                        break;
+                    }
+                }
+            }
+            if let Err(e) = self.store.set_in(FILE_ROUTES_TREE, &route.name, &route) {
+                tracing::warn!("Failed to record route {} from the routes file: {}", route.name, e);
+            }
+        }
+    }
+
+    /// Entries dropped from the file stop being tracked; the routes stay.
+    fn forget_removed(&self, routes: &[Route]) -> Result<()> {
+        let names: HashSet<&str> = routes.iter().map(|route| route.name.as_str()).collect();
+        for recorded in self.store.list_in::<Route>(FILE_ROUTES_TREE)? {
+            if !names.contains(recorded.name.as_str()) {
+                self.store.delete_from(FILE_ROUTES_TREE, &recorded.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_deleted(&self, name: &str) -> bool {
+        matches!(self.store.get_from::<bool>(DELETED_ROUTES_TREE, name), Ok(Some(_)))
+    }
+
+    pub fn responses(&self) -> &ResponseCache {
+        &self.responses
+    }
+
+    fn apply_put(&self, route: Route) -> Result<()> {
+        route.validate()?;
+        self.store.set_in(ROUTES_TREE, &route.name, &route)?;
+        self.store.delete_from(DELETED_ROUTES_TREE, &route.name)?;
+        if route.cache.is_none() {
+            self.responses.remove(&route.name);
+        }
+        self.reload()
+    }
+
+    fn apply_set_weights(&self, name: &str, weights: &HashMap<String, u32>) -> Result<()> {
+        let updated = self.store.update_in(ROUTES_TREE, name, |mut route: Route| {
+            for (backend, weight) in weights {
+                route
+                    .split
+                    .iter_mut()
+                    .find(|b| &b.name == backend)
+                    .ok_or_else(|| {
+                        Error::BadRequest(format!("route {} has no backend {}", name, backend))
+                    })?
+                    .weight = *weight;
+            }
+            route.validate()?;
+            Ok(route)
+        })?;
+        if updated.is_none() {
+            return Err(Error::ServiceNotFound(name.to_string()));
+        }
+        self.reload()
+    }
+
+    fn apply_delete(&self, name: &str) -> Result<()> {
+        self.store.set_in(DELETED_ROUTES_TREE, name, &true)?;
+        self.store.delete_from(ROUTES_TREE, name)?;
+        self.responses.remove(name);
+        self.reload()
+    }
+
+    /// The first route matching `request`.
+    pub fn find(&self, request: &Request) -> Option<Arc<Route>> {
+        self.routes
+            .load()
+            .iter()
+            .find(|route| route.matches.matches(request))
+            .cloned()
+    }
+}
+
+impl StateMachine for RouteTable {
+    fn apply(&self, command: &[u8]) -> Result<()> {
+        let command: RouteCommand =
+            serde_json::from_slice(command).map_err(|e| Error::Storage(e.to_string()))?;
+        match command {
+            RouteCommand::Put { route } => self.apply_put(*route),
+            RouteCommand::SetWeights { name, weights } => self.apply_set_weights(&name, &weights),
+            RouteCommand::Delete { name } => self.apply_delete(&name),
+        }
+    }
+}
+
+/// Routes don't compare directly; their serialized forms do.
+fn same_route(a: &Route, b: &Route) -> bool {
+    matches!(
+        (serde_json::to_value(a), serde_json::to_value(b)),
+        (Ok(a), Ok(b)) if a == b
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(name: &str) -> Route {
+        serde_json::from_value(serde_json::json!({ "name": name, "service": "echo" })).unwrap()
+    }
+
+    #[test]
+    fn applied_commands_change_the_table_and_tombstones() {
+        let path = std::env::temp_dir().join(format!("lodestone-routes-{}", uuid::Uuid::new_v4()));
+        let store = Arc::new(Store::new(&path).unwrap());
+        let table = RouteTable::load(store).unwrap();
+        let put = RouteCommand::Put { route: Box::new(route("a")) }.encode().unwrap();
+        let delete = RouteCommand::Delete { name: "a".to_string() }.encode().unwrap();
+
+        table.apply(&put).unwrap();
+        assert!(table.get("a").is_some());
+        table.apply(&delete).unwrap();
+        assert!(table.get("a").is_none());
+        assert!(table.is_deleted("a"));
+        table.apply(&put).unwrap();
+        assert!(!table.is_deleted("a"));
+
+        let invalid = RouteCommand::Put { route: Box::new(route("")) }.encode().unwrap();
+        assert!(matches!(table.apply(&invalid), Err(Error::BadRequest(_))));
+
+        drop(table);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn set_weights_updates_the_stored_route_or_leaves_it_alone() {
+        let path = std::env::temp_dir().join(format!("lodestone-routes-{}", uuid::Uuid::new_v4()));
+        let store = Arc::new(Store::new(&path).unwrap());
+        let table = RouteTable::load(store).unwrap();
+        let mut split = route("a");
+        split.split = serde_json::from_value(serde_json::json!([
+            { "name": "v1", "weight": 100 },
+            { "name": "v2", "weight": 0 },
+        ]))
+        .unwrap();
+        table.apply(&RouteCommand::Put { route: Box::new(split) }.encode().unwrap()).unwrap();
+        let set_weights = |name: &str, weights: &[(&str, u32)]| {
+            let weights = weights.iter().map(|(b, w)| (b.to_string(), *w)).collect();
+            table.apply(&RouteCommand::SetWeights { name: name.to_string(), weights }.encode().unwrap())
+        };
+        let weights = || table.get("a").unwrap().split.iter().map(|b| b.weight).collect::<Vec<_>>();
+
+        set_weights("a", &[("v1", 50), ("v2", 50)]).unwrap();
+        assert_eq!(weights(), vec![50, 50]);
+        assert!(matches!(set_weights("a", &[("v1", 0), ("v3", 1)]), Err(Error::BadRequest(_))));
+        assert!(matches!(set_weights("a", &[("v1", 0), ("v2", 0)]), Err(Error::BadRequest(_))));
+        assert_eq!(weights(), vec![50, 50]);
+        assert!(matches!(set_weights("b", &[]), Err(Error::ServiceNotFound(_))));
+
+        drop(table);
+        std::fs::remove_dir_all(path).ok();
+    }
+}
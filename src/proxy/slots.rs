@@ -0,0 +1,34 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+/// Per-route counts of something in flight, such as shadow copies or
+/// upgraded connections. Over a route's limit, the caller is turned away
+/// rather than queued.
+#[derive(Debug, Clone, Default)]
+pub struct RouteSlots {
+    in_flight: Arc<DashMap<String, Arc<AtomicUsize>>>,
+}
+
+/// Keeps one slot taken until dropped.
+#[derive(Debug)]
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RouteSlots {
+    pub fn try_acquire(&self, route: &str, limit: usize) -> Option<Slot> {
+        let counter = self.in_flight.entry(route.to_string()).or_default().clone();
+        counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < limit).then_some(count + 1)
+            })
+            .ok()?;
+        Some(Slot(counter))
+    }
+}
@@ -0,0 +1,246 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_RECORD_LEN: usize = 16 * 1024;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Wait between peeks while the ClientHello is still arriving.
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+enum Parsed {
+    /// More bytes are needed to decide.
+    Incomplete,
+    /// A ClientHello, and the server name it asked for, if any.
+    ClientHello(Option<String>),
+    NotTls,
+}
+
+/// The server name in the TLS ClientHello the client opened with. The
+/// bytes are only peeked, so the whole handshake still reaches the
+/// instance and TLS is never terminated here. `None` if the client did
+/// not send SNI.
+pub async fn peek_server_name(stream: &TcpStream, timeout: Duration) -> io::Result<Option<String>> {
+    let deadline = Instant::now() + timeout;
+    let mut buffer = vec![0; RECORD_HEADER_LEN + MAX_RECORD_LEN];
+    loop {
+        let read = tokio::time::timeout_at(deadline, stream.peek(&mut buffer))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no ClientHello in time"))??;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match parse(&buffer[..read]) {
+            Parsed::ClientHello(name) => return Ok(name),
+            Parsed::NotTls => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a TLS ClientHello",
+                ))
+            }
+            Parsed::Incomplete if read == buffer.len() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "ClientHello too large",
+                ))
+            }
+            // `peek` returns at once while any data is buffered, so give
+            // the rest of the record time to arrive.
+            Parsed::Incomplete => {
+                tokio::time::sleep_until(deadline.min(Instant::now() + PEEK_INTERVAL)).await
+            }
+        }
+    }
+}
+
+/// Reads big-endian fields off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|bytes| (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+    }
+
+    /// A vector with a `width`-byte length prefix.
+    fn vector(&mut self, width: usize) -> Option<Reader<'a>> {
+        let len = match width {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?,
+        };
+        self.take(len).map(Reader)
+    }
+}
+
+fn parse(bytes: &[u8]) -> Parsed {
+    if bytes.len() < RECORD_HEADER_LEN {
+        return Parsed::Incomplete;
+    }
+    if bytes[0] != CONTENT_TYPE_HANDSHAKE {
+        return Parsed::NotTls;
+    }
+    let record_len = u16::from_be_bytes([bytes[3], bytes[4]]) as usize;
+    if record_len > MAX_RECORD_LEN {
+        return Parsed::NotTls;
+    }
+    let Some(record) = bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len) else {
+        return Parsed::Incomplete;
+    };
+    // A ClientHello split over several records is not supported; one
+    // record holds up to 16 KiB, far more than clients send.
+    match client_hello_server_name(Reader(record)) {
+        Some(name) => Parsed::ClientHello(name),
+        None => Parsed::NotTls,
+    }
+}
+
+/// `None` if the record is not a well-formed ClientHello.
+fn client_hello_server_name(mut record: Reader) -> Option<Option<String>> {
+    if record.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let len = record.u24()?;
+    let mut hello = Reader(record.take(len)?);
+    hello.take(2 + 32)?; // legacy_version, random
+    hello.vector(1)?; // legacy_session_id
+    hello.vector(2)?; // cipher_suites
+    hello.vector(1)?; // legacy_compression_methods
+    if hello.0.is_empty() {
+        return Some(None);
+    }
+
+    let mut extensions = hello.vector(2)?;
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = extensions.vector(2)?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = data.vector(2)?;
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vector(2)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name.0).ok()?;
+                return Some(Some(name.to_ascii_lowercase()));
+            }
+        }
+    }
+    Some(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(width: usize, body: &[u8]) -> Vec<u8> {
+        let len = (body.len() as u32).to_be_bytes();
+        let mut bytes = len[4 - width..].to_vec();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = kind.to_be_bytes().to_vec();
+        bytes.extend(vector(2, data));
+        bytes
+    }
+
+    fn server_name(name: &str) -> Vec<u8> {
+        let mut entry = vec![NAME_TYPE_HOST_NAME];
+        entry.extend(vector(2, name.as_bytes()));
+        extension(EXTENSION_SERVER_NAME, &vector(2, &entry))
+    }
+
+    fn client_hello(extensions: Option<Vec<u8>>) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0; 32]);
+        hello.extend(vector(1, &[]));
+        hello.extend(vector(2, &[0x13, 0x01]));
+        hello.extend(vector(1, &[0]));
+        if let Some(extensions) = extensions {
+            hello.extend(vector(2, &extensions));
+        }
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(vector(3, &hello));
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend(vector(2, &handshake));
+        record
+    }
+
+    #[test]
+    fn finds_server_name() {
+        let mut extensions = extension(0x000a, &[0, 2, 0, 0x1d]);
+        extensions.extend(server_name("DB.Example.com"));
+        let parsed = parse(&client_hello(Some(extensions)));
+        assert!(matches!(parsed, Parsed::ClientHello(Some(name)) if name == "db.example.com"));
+    }
+
+    #[test]
+    fn client_hello_without_sni() {
+        let extensions = extension(0x000a, &[0, 2, 0, 0x1d]);
+        assert!(matches!(
+            parse(&client_hello(Some(extensions))),
+            Parsed::ClientHello(None)
+        ));
+        assert!(matches!(
+            parse(&client_hello(None)),
+            Parsed::ClientHello(None)
+        ));
+    }
+
+    #[test]
+    fn truncated_record_is_incomplete() {
+        let hello = client_hello(Some(server_name("db.example.com")));
+        assert!(matches!(parse(&hello[..3]), Parsed::Incomplete));
+        assert!(matches!(
+            parse(&hello[..hello.len() - 1]),
+            Parsed::Incomplete
+        ));
+    }
+
+    #[test]
+    fn oversized_record_is_not_tls() {
+        let mut hello = client_hello(None);
+        hello[3..5].copy_from_slice(&((MAX_RECORD_LEN + 1) as u16).to_be_bytes());
+        assert!(matches!(parse(&hello), Parsed::NotTls));
+    }
+
+    #[test]
+    fn other_traffic_is_not_tls() {
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\n"), Parsed::NotTls));
+    }
+
+    #[test]
+    fn malformed_client_hello_is_not_tls() {
+        let mut hello = client_hello(Some(server_name("db.example.com")));
+        // Claim a longer handshake than the record holds.
+        hello[6..9].copy_from_slice(&[0, 0xff, 0xff]);
+        assert!(matches!(parse(&hello), Parsed::NotTls));
+    }
+}
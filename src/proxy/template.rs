@@ -0,0 +1,180 @@
+use std::net::IpAddr;
+
+use crate::prelude::*;
+use crate::service::Service;
+
+const METADATA_PREFIX: &str = "instance.metadata.";
+
+/// Variables besides `{instance.metadata.<key>}`.
+const VARIABLES: [&str; 9] = [
+    "route.name",
+    "service.name",
+    "instance.id",
+    "instance.address",
+    "instance.port",
+    "instance.region",
+    "instance.zone",
+    "client.ip",
+    "request.id",
+];
+
+/// What a header template can refer to, for one attempt on one instance.
+pub struct Context<'a> {
+    pub route: &'a str,
+    pub service: &'a str,
+    pub instance: &'a Service,
+    pub client: IpAddr,
+    pub request_id: Option<&'a str>,
+}
+
+impl Context<'_> {
+    fn lookup(&self, name: &str) -> Option<String> {
+        if let Some(key) = name.strip_prefix(METADATA_PREFIX) {
+            return Some(self.instance.metadata.get(key).cloned().unwrap_or_default());
+        }
+        Some(match name {
+            "route.name" => self.route.to_string(),
+            "service.name" => self.service.to_string(),
+            "instance.id" => self.instance.id.clone(),
+            "instance.address" => self.instance.address.clone(),
+            "instance.port" => self.instance.port.to_string(),
+            "instance.region" => self.instance.locality.region.clone(),
+            "instance.zone" => self.instance.locality.zone.clone(),
+            "client.ip" => self.client.to_string(),
+            "request.id" => self.request_id.unwrap_or_default().to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// Split `template` into literal text and `{variable}` names.
+fn parts(template: &str) -> impl Iterator<Item = Result<Part<'_>>> {
+    let mut rest = template;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let Some(start) = rest.find('{') else {
+            let literal = rest;
+            rest = "";
+            return Some(Ok(Part::Literal(literal)));
+        };
+        if start > 0 {
+            let literal = &rest[..start];
+            rest = &rest[start..];
+            return Some(Ok(Part::Literal(literal)));
+        }
+        let Some(end) = rest.find('}') else {
+            rest = "";
+            return Some(Err(Error::BadRequest(format!(
+                "unclosed variable in header template: {}",
+                template
+            ))));
+        };
+        let name = &rest[1..end];
+        rest = &rest[end + 1..];
+        Some(Ok(Part::Variable(name)))
+    })
+}
+
+enum Part<'a> {
+    Literal(&'a str),
+    Variable(&'a str),
+}
+
+/// Reject templates with unknown variables, so mistakes surface when the
+/// route is saved rather than as empty headers.
+pub fn validate(template: &str) -> Result<()> {
+    for part in parts(template) {
+        if let Part::Variable(name) = part? {
+            let known = VARIABLES.contains(&name)
+                || name
+                    .strip_prefix(METADATA_PREFIX)
+                    .is_some_and(|key| !key.is_empty());
+            if !known {
+                return Err(Error::BadRequest(format!(
+                    "unknown variable {{{}}} in header template",
+                    name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `template` with its variables filled in; missing metadata renders
+/// empty.
+pub fn render(template: &str, context: &Context) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    for part in parts(template).flatten() {
+        match part {
+            Part::Literal(literal) => rendered.push_str(literal),
+            Part::Variable(name) => rendered.push_str(&context.lookup(name).unwrap_or_default()),
+        }
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance() -> Service {
+        let mut instance = Service::new("orders".to_string(), "10.0.0.5".to_string(), 8080);
+        instance.id = "orders-1".to_string();
+        instance
+            .metadata
+            .insert("version".to_string(), "1.4.2".to_string());
+        instance
+    }
+
+    fn render_for(template: &str) -> String {
+        let instance = instance();
+        let context = Context {
+            route: "orders-v2",
+            service: "orders",
+            instance: &instance,
+            client: "192.0.2.7".parse().unwrap(),
+            request_id: Some("abc-123"),
+        };
+        render(template, &context)
+    }
+
+    #[test]
+    fn renders_variables_between_literals() {
+        assert_eq!(
+            render_for("{service.name}/{instance.id}@{instance.metadata.version}"),
+            "orders/orders-1@1.4.2"
+        );
+        assert_eq!(
+            render_for("route={route.name} at {instance.address}:{instance.port}"),
+            "route=orders-v2 at 10.0.0.5:8080"
+        );
+        assert_eq!(render_for("{client.ip} {request.id}"), "192.0.2.7 abc-123");
+        assert_eq!(render_for("no variables"), "no variables");
+        assert_eq!(render_for(""), "");
+    }
+
+    #[test]
+    fn missing_metadata_renders_empty() {
+        assert_eq!(render_for("v{instance.metadata.track}"), "v");
+        assert_eq!(render_for("[{instance.zone}]"), "[]");
+    }
+
+    #[test]
+    fn validates_known_variables() {
+        assert!(validate("{service.name}-{instance.metadata.version}").is_ok());
+        assert!(validate("plain").is_ok());
+        assert!(validate("{nope}").is_err());
+        assert!(validate("{instance.metadata.}").is_err());
+        assert!(validate("{}").is_err());
+    }
+
+    #[test]
+    fn rejects_unclosed_braces() {
+        assert!(validate("{service.name").is_err());
+        assert!(validate("ok {service.name} then {").is_err());
+        // Rendering never fails; the unclosed tail is dropped.
+        assert_eq!(render_for("{service.name} {instance.id"), "orders ");
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::http::{HeaderMap, HeaderValue};
+use http_body::{Body, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use tokio::time::{Instant, Sleep};
+use tower::BoxError;
+
+/// Milliseconds the caller is still prepared to wait. Lodestone sends it
+/// upstream so services further down the chain can give up in time.
+pub const DEADLINE_HEADER: &str = "x-lodestone-deadline";
+
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// Largest value `grpc-timeout` allows before switching units.
+const GRPC_TIMEOUT_MAX_DIGITS: u64 = 99_999_999;
+
+/// Time left on the caller's deadline, if it sent one.
+pub fn incoming(headers: &HeaderMap) -> Option<Duration> {
+    let value = |name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+    let lodestone = value(DEADLINE_HEADER)
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis);
+    let grpc = value(GRPC_TIMEOUT_HEADER).and_then(parse_grpc_timeout);
+    lodestone.into_iter().chain(grpc).min()
+}
+
+/// Pass the time left on to the upstream, as `grpc-timeout` for gRPC
+/// requests and `x-lodestone-deadline` otherwise.
+pub fn propagate(headers: &mut HeaderMap, remaining: Duration) {
+    let (name, value) = if super::grpc::is_grpc(headers) {
+        (GRPC_TIMEOUT_HEADER, format_grpc_timeout(remaining))
+    } else {
+        (DEADLINE_HEADER, remaining.as_millis().to_string())
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name, value);
+    }
+}
+
+/// Parses `grpc-timeout`: up to eight digits followed by a unit.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    if amount.is_empty() || amount.len() > 8 || !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount.checked_mul(3600)?),
+        "M" => Duration::from_secs(amount.checked_mul(60)?),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+fn format_grpc_timeout(timeout: Duration) -> String {
+    let millis = timeout.as_millis() as u64;
+    if millis <= GRPC_TIMEOUT_MAX_DIGITS {
+        format!("{}m", millis)
+    } else {
+        format!("{}S", timeout.as_secs().min(GRPC_TIMEOUT_MAX_DIGITS))
+    }
+}
+
+/// The upstream stopped sending the response body.
+#[derive(Debug)]
+pub struct IdleTimedOut;
+
+impl fmt::Display for IdleTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "response body idle timeout")
+    }
+}
+
+impl std::error::Error for IdleTimedOut {}
+
+pin_project! {
+    /// Fails a body that goes `idle` without yielding a frame.
+    pub struct IdleTimeout<B> {
+        #[pin]
+        inner: B,
+        #[pin]
+        sleep: Sleep,
+        idle: Duration,
+    }
+}
+
+impl<B> IdleTimeout<B> {
+    pub fn new(inner: B, idle: Duration) -> Self {
+        Self {
+            inner,
+            sleep: tokio::time::sleep(idle),
+            idle,
+        }
+    }
+}
+
+impl<B> Body for IdleTimeout<B>
+where
+    B: Body,
+    B::Error: Into<BoxError>,
+{
+    type Data = B::Data;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        match this.inner.poll_frame(cx) {
+            Poll::Ready(frame) => {
+                this.sleep.as_mut().reset(Instant::now() + *this.idle);
+                Poll::Ready(frame.map(|frame| frame.map_err(Into::into)))
+            }
+            Poll::Pending => match this.sleep.poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Some(Err(IdleTimedOut.into()))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_grpc_timeout_units() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("10u"), Some(Duration::from_micros(10)));
+        assert_eq!(parse_grpc_timeout("7n"), Some(Duration::from_nanos(7)));
+        assert_eq!(
+            parse_grpc_timeout("99999999H"),
+            Some(Duration::from_secs(99_999_999 * 3600))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_grpc_timeout() {
+        for value in ["", "S", "10", "10x", "123456789m", "+5S", "-5S", " 5S", "1.5S"] {
+            assert_eq!(parse_grpc_timeout(value), None, "{:?}", value);
+        }
+        assert_eq!(parse_grpc_timeout("18446744073709551615H"), None);
+    }
+
+    #[test]
+    fn formats_grpc_timeout_within_eight_digits() {
+        assert_eq!(format_grpc_timeout(Duration::from_millis(1500)), "1500m");
+        assert_eq!(format_grpc_timeout(Duration::from_secs(200_000)), "200000S");
+        assert_eq!(format_grpc_timeout(Duration::from_secs(u64::MAX)), "99999999S");
+        let formatted = format_grpc_timeout(Duration::from_secs(42));
+        assert_eq!(parse_grpc_timeout(&formatted), Some(Duration::from_secs(42)));
+    }
+
+    #[test]
+    fn incoming_takes_the_shorter_deadline() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(incoming(&headers), None);
+        headers.insert(DEADLINE_HEADER, HeaderValue::from_static("800"));
+        headers.insert(GRPC_TIMEOUT_HEADER, HeaderValue::from_static("1S"));
+        assert_eq!(incoming(&headers), Some(Duration::from_millis(800)));
+        headers.insert(GRPC_TIMEOUT_HEADER, HeaderValue::from_static("999999999S"));
+        assert_eq!(incoming(&headers), Some(Duration::from_millis(800)));
+    }
+}
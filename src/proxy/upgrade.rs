@@ -0,0 +1,64 @@
+use std::io;
+use std::time::Duration;
+
+use axum::http::{header, HeaderMap};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Whether the client asks to switch protocols, e.g. to WebSocket.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    let connection_upgrade = headers
+        .get_all(header::CONNECTION)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    connection_upgrade && headers.contains_key(header::UPGRADE)
+}
+
+/// Copy bytes both ways between an upgraded client and upstream
+/// connection until both sides have closed, or nothing has moved in
+/// either direction for `idle`, if set. WebSocket frames, including close
+/// and ping/pong, pass through untouched. Returns the bytes sent each way.
+pub async fn tunnel<C, U>(client: C, upstream: U, idle: Option<Duration>) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite,
+    U: AsyncRead + AsyncWrite,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let mut client_buffer = vec![0; BUFFER_SIZE];
+    let mut upstream_buffer = vec![0; BUFFER_SIZE];
+    let (mut client_open, mut upstream_open) = (true, true);
+    let (mut sent, mut received) = (0, 0);
+
+    while client_open || upstream_open {
+        tokio::select! {
+            read = client_read.read(&mut client_buffer), if client_open => match read? {
+                0 => {
+                    client_open = false;
+                    upstream_write.shutdown().await?;
+                }
+                n => {
+                    upstream_write.write_all(&client_buffer[..n]).await?;
+                    sent += n as u64;
+                }
+            },
+            read = upstream_read.read(&mut upstream_buffer), if upstream_open => match read? {
+                0 => {
+                    upstream_open = false;
+                    client_write.shutdown().await?;
+                }
+                n => {
+                    client_write.write_all(&upstream_buffer[..n]).await?;
+                    received += n as u64;
+                }
+            },
+            _ = tokio::time::sleep(idle.unwrap_or_default()), if idle.is_some() => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "tunnelled connection idle"));
+            }
+        }
+    }
+    Ok((sent, received))
+}
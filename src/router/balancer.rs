@@ -1,30 +1,574 @@
-use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use dashmap::DashMap;
-use crate::service::Service;
+use std::time::Instant;
+use arc_swap::ArcSwap;
+use chrono::Utc;
+use tokio::sync::broadcast::error::RecvError;
+use crate::config::LoadBalancerConfig;
+use crate::discovery::{RegistryEvent, ServiceRegistry};
+use crate::health::HealthStatus;
+use crate::prelude::*;
+use crate::service::{Locality, Service, Subset};
+use super::circuit_breaker::CircuitBreakers;
+use super::hashing::{HashOn, RequestAttributes, HASH_ON_KEY, STICKY_COOKIE_KEY};
+use super::strategy::{BalancingStrategy, Endpoint, Ramp, StrategyKind, STRATEGY_KEY};
 
+type Pools = HashMap<String, Arc<Pool>>;
+
+/// Routable endpoints of one service name and the strategy that picks
+/// between them.
+#[derive(Debug)]
+struct Pool {
+    /// Every routable endpoint. Strategies always see the whole list, so
+    /// hash tables built from it stay put as candidates come and go.
+    routable: Vec<Endpoint>,
+    /// IDs of the endpoints in the preferred locality tier.
+    preferred: HashSet<String>,
+    kind: StrategyKind,
+    strategy: Arc<dyn BalancingStrategy>,
+    hash_on: Option<HashOn>,
+    sticky_cookie: Option<String>,
+}
+
+/// An instance handed out by the balancer. It counts as in flight for
+/// load-aware strategies until dropped.
+#[derive(Debug)]
+pub struct Selection {
+    pub service: Service,
+    /// `Set-Cookie` value pinning the client to this instance, when the
+    /// service uses sticky sessions and the client is not pinned yet.
+    pub set_cookie: Option<String>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for Selection {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Selects instances from a snapshot of routable endpoints per service
+/// name. The snapshot is swapped atomically by `sync`, so lookups never
+/// take a lock.
 #[derive(Debug, Clone)]
 pub struct LoadBalancer {
-    services: Arc<DashMap<String, Vec<Service>>>,
+    pools: Arc<ArcSwap<Pools>>,
+    config: Arc<LoadBalancerConfig>,
+    locality: Arc<Locality>,
+    breakers: CircuitBreakers,
+}
+
+/// Registry state mirrored by the sync task. Only that task touches it.
+#[derive(Default)]
+struct Mirror {
+    instances: HashMap<String, Service>,
+    health: HashMap<String, HealthStatus>,
+    in_flight: HashMap<String, Arc<AtomicUsize>>,
+    /// When each routable instance entered rotation; `None` for instances
+    /// that were already serving when the balancer started.
+    routable_since: HashMap<String, Option<Instant>>,
+    loaded: bool,
+}
+
+impl Mirror {
+    fn routable(&self, name: &str) -> Vec<Service> {
+        let mut routable: Vec<Service> = self
+            .instances
+            .values()
+            .filter(|service| service.name == name && service.is_available())
+            .filter(|service| self.health.get(&service.id) != Some(&HealthStatus::Unhealthy))
+            .cloned()
+            .collect();
+        // Stable order so position-based strategies behave predictably.
+        routable.sort_by(|a, b| a.id.cmp(&b.id));
+        routable
+    }
+
+    fn has_name(&self, name: &str) -> bool {
+        self.instances.values().any(|service| service.name == name)
+    }
+
+    /// Time until the next maintenance window among the instances ends.
+    fn next_expiry(&self) -> Option<std::time::Duration> {
+        let now = Utc::now();
+        self.instances
+            .values()
+            .filter_map(|service| service.maintenance.as_ref()?.expires_at)
+            .filter(|expires| *expires > now)
+            .min()
+            .and_then(|expires| (expires - now).to_std().ok())
+    }
+}
+
+/// A per-service setting taken from the first instance that carries it in
+/// its metadata.
+fn metadata_setting<T: std::str::FromStr<Err = Error>>(
+    name: &str,
+    instances: &[Service],
+    key: &str,
+) -> Option<T> {
+    let value = instances.iter().find_map(|service| service.metadata.get(key))?;
+    match value.parse() {
+        Ok(setting) => Some(setting),
+        Err(e) => {
+            tracing::warn!("Ignoring {} for {}: {}", key, name, e);
+            None
+        }
+    }
 }
 
 impl LoadBalancer {
-    pub fn new() -> Self {
+    pub fn new(config: LoadBalancerConfig, locality: Locality, breakers: CircuitBreakers) -> Self {
         Self {
-            services: Arc::new(DashMap::new()),
+            pools: Arc::new(ArcSwap::from_pointee(HashMap::new())),
+            config: Arc::new(config),
+            locality: Arc::new(locality),
+            breakers,
         }
     }
 
-    pub fn add_service(&self, service: Service) {
-        self.services
-            .entry(service.name.clone())
-            .or_default()
-            .push(service);
+    /// Maintenance windows can start between snapshots and breakers open
+    /// per request, so both are checked at pick time.
+    fn accepts_traffic(&self, endpoint: &Endpoint) -> bool {
+        endpoint.service.is_available() && self.breakers.allows_calls(&endpoint.service.id)
     }
 
-    pub fn get_service(&self, name: &str) -> Option<Service> {
-        self.services.get(name).and_then(|services| {
-            services.choose(&mut rand::thread_rng()).cloned()
+    /// Pick a routable instance of `name` for a request, avoiding the
+    /// instances in `exclude` unless nothing else is left. With a `subset`,
+    /// only matching instances are considered, outside the preferred
+    /// locality if need be. Distinguishes a service nobody registered from
+    /// one whose instances are all out of rotation.
+    pub fn get_service(
+        &self,
+        name: &str,
+        subset: Option<&Subset>,
+        request: &RequestAttributes,
+        exclude: &[String],
+    ) -> Result<Selection> {
+        let pools = self.pools.load();
+        let pool = pools
+            .get(name)
+            .ok_or_else(|| Error::ServiceNotFound(name.to_string()))?;
+
+        // Candidates are the preferred locality tier, narrowed to the
+        // subset if there is one. When nothing there takes traffic, e.g.
+        // every local breaker is open or maintenance began since the pool
+        // was built, every routable instance that matches is a candidate.
+        let in_subset = |e: &Endpoint| subset.is_none_or(|subset| subset.matches(&e.service));
+        let preferred = |e: &Endpoint| pool.preferred.contains(&e.service.id);
+        let spill = !pool
+            .routable
+            .iter()
+            .any(|e| preferred(e) && in_subset(e) && self.accepts_traffic(e));
+        let candidate = |e: &Endpoint| in_subset(e) && (spill || preferred(e));
+
+        // A sticky client goes back to its instance while it stays routable.
+        let pinned = pool.sticky_cookie.as_deref().and_then(|cookie| {
+            let id = request.cookie(cookie)?;
+            pool.routable
+                .iter()
+                .find(|e| e.service.id == id && !exclude.contains(&e.service.id))
+                .filter(|e| candidate(e) && self.accepts_traffic(e))
+        });
+
+        let endpoint = match pinned {
+            Some(endpoint) => endpoint.clone(),
+            None => {
+                let key = pool
+                    .hash_on
+                    .as_ref()
+                    .filter(|_| pool.kind.uses_key())
+                    .and_then(|on| request.hash(on));
+
+                let available = |e: &Endpoint| candidate(e) && self.accepts_traffic(e);
+                let fresh = |e: &Endpoint| available(e) && !exclude.contains(&e.service.id);
+                pool.strategy
+                    .pick_eligible(&pool.routable, &fresh, key)
+                    // Every instance was tried already; go round again.
+                    .or_else(|| pool.strategy.pick_eligible(&pool.routable, &available, key))
+                    .ok_or_else(|| Error::NoHealthyInstance(name.to_string()))?
+            }
+        };
+
+        let set_cookie = match (&pool.sticky_cookie, pinned) {
+            (Some(cookie), None) => Some(format!(
+                "{}={}; Path=/; HttpOnly",
+                cookie, endpoint.service.id
+            )),
+            _ => None,
+        };
+
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        Ok(Selection {
+            service: endpoint.service,
+            set_cookie,
+            in_flight: endpoint.in_flight,
         })
     }
-}
\ No newline at end of file
+
+    /// Strategy for `name`: instance metadata first, then the per-service
+    /// config override, then the configured default.
+    fn strategy_for(&self, name: &str, instances: &[Service]) -> StrategyKind {
+        metadata_setting(name, instances, STRATEGY_KEY)
+            .or_else(|| self.config.services.get(name).copied())
+            .unwrap_or(self.config.strategy)
+    }
+
+    /// Total weight of `services` at locality `priority`.
+    fn capacity<'a>(&self, services: impl IntoIterator<Item = &'a Service>, priority: u8) -> u64 {
+        services
+            .into_iter()
+            .filter(|service| self.locality.priority(&service.locality) == priority)
+            .map(|service| service.weight.max(1) as u64)
+            .sum()
+    }
+
+    /// Keep the closest localities that together hold enough healthy
+    /// capacity, spilling over to farther ones only while they fall short.
+    fn by_locality(&self, registered: &[&Service], routable: Vec<Service>) -> Vec<Service> {
+        if self.locality.is_empty() {
+            return routable;
+        }
+
+        let threshold = self.config.locality.min_healthy_percent as u64;
+
+        let mut priorities: Vec<u8> = registered
+            .iter()
+            .map(|service| self.locality.priority(&service.locality))
+            .collect();
+        priorities.sort_unstable();
+        priorities.dedup();
+
+        let (mut total, mut healthy) = (0, 0);
+        for priority in priorities {
+            total += self.capacity(registered.iter().copied(), priority);
+            healthy += self.capacity(&routable, priority);
+            if healthy > 0 && healthy * 100 >= total * threshold {
+                return routable
+                    .into_iter()
+                    .filter(|service| self.locality.priority(&service.locality) <= priority)
+                    .collect();
+            }
+        }
+        routable
+    }
+
+    fn build_pool(&self, mirror: &mut Mirror, name: &str, previous: Option<&Arc<Pool>>) -> Pool {
+        let instances = mirror.routable(name);
+        let kind = self.strategy_for(name, &instances);
+
+        // Keep the existing strategy so round-robin position and weights
+        // survive endpoint changes.
+        let strategy = match previous {
+            Some(pool) if pool.kind == kind => pool.strategy.clone(),
+            _ => kind.build(),
+        };
+
+        let hash_on = metadata_setting(name, &instances, HASH_ON_KEY)
+            .or_else(|| self.config.hash_on.clone());
+        let sticky_cookie = instances
+            .iter()
+            .find_map(|service| service.metadata.get(STICKY_COOKIE_KEY).cloned())
+            .or_else(|| self.config.sticky_cookie.clone());
+
+        // Forget instances that left rotation so they ramp up again on return.
+        let left: Vec<String> = mirror
+            .instances
+            .values()
+            .filter(|service| service.name == name)
+            .filter(|service| !instances.iter().any(|routable| routable.id == service.id))
+            .map(|service| service.id.clone())
+            .collect();
+        for id in left {
+            mirror.routable_since.remove(&id);
+        }
+
+        let registered: Vec<&Service> = mirror
+            .instances
+            .values()
+            .filter(|service| service.name == name)
+            .collect();
+        let preferred: HashSet<String> = self
+            .by_locality(&registered, instances.clone())
+            .into_iter()
+            .map(|service| service.id)
+            .collect();
+
+        let slow_start = &self.config.slow_start;
+        let routable: Vec<Endpoint> = instances
+            .into_iter()
+            .map(|service| {
+                let since = *mirror
+                    .routable_since
+                    .entry(service.id.clone())
+                    .or_insert_with(|| mirror.loaded.then(Instant::now));
+                Endpoint {
+                    in_flight: mirror.in_flight.entry(service.id.clone()).or_default().clone(),
+                    ramp: since.filter(|_| slow_start.window > 0).map(|since| Ramp {
+                        since,
+                        window: slow_start.window(),
+                        curve: slow_start.curve,
+                        min_fraction: slow_start.min_weight_percent as f64 / 100.0,
+                    }),
+                    service,
+                }
+            })
+            .collect();
+
+        Pool {
+            routable,
+            preferred,
+            kind,
+            strategy,
+            hash_on,
+            sticky_cookie,
+        }
+    }
+
+    /// Keep the endpoint snapshot in step with the registry: registrations,
+    /// deregistrations, health changes and maintenance/drain updates.
+    pub async fn sync(self, registry: ServiceRegistry) {
+        // Subscribe before the initial load so nothing slips in between.
+        let mut events = registry.subscribe();
+        let mut mirror = Mirror::default();
+        self.resync(&registry, &mut mirror).await;
+
+        loop {
+            let expiry = mirror.next_expiry();
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => self.apply(&registry, &mut mirror, event).await,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Load balancer missed {} registry events, resyncing", skipped);
+                        self.resync(&registry, &mut mirror).await;
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = tokio::time::sleep(expiry.unwrap_or_default()), if expiry.is_some() => {
+                    self.lift_maintenance(&mut mirror);
+                }
+            }
+        }
+    }
+
+    async fn resync(&self, registry: &ServiceRegistry, mirror: &mut Mirror) {
+        let services = match registry.list_services().await {
+            Ok(services) => services,
+            Err(e) => {
+                tracing::error!("Failed to load services for load balancer: {}", e);
+                return;
+            }
+        };
+
+        let health = registry.health();
+        mirror.health = services
+            .iter()
+            .map(|service| (service.id.clone(), health.status(&service.id)))
+            .collect();
+        mirror.instances = services
+            .into_iter()
+            .map(|service| (service.id.clone(), service))
+            .collect();
+        mirror.in_flight.retain(|id, _| mirror.instances.contains_key(id));
+        mirror.routable_since.retain(|id, _| mirror.instances.contains_key(id));
+
+        let previous = self.pools.load();
+        let mut names: Vec<String> = mirror.instances.values().map(|s| s.name.clone()).collect();
+        names.sort();
+        names.dedup();
+
+        let mut pools = Pools::new();
+        for name in names {
+            let pool = self.build_pool(mirror, &name, previous.get(&name));
+            pools.insert(name, Arc::new(pool));
+        }
+        self.pools.store(Arc::new(pools));
+        mirror.loaded = true;
+    }
+
+    async fn apply(&self, registry: &ServiceRegistry, mirror: &mut Mirror, event: RegistryEvent) {
+        let mut affected = Vec::new();
+
+        match event {
+            RegistryEvent::Registered { service } | RegistryEvent::Updated { service } => {
+                self.breakers.refresh(&service);
+                mirror
+                    .health
+                    .entry(service.id.clone())
+                    .or_insert_with(|| registry.health().status(&service.id));
+                if let Some(previous) = mirror.instances.insert(service.id.clone(), service.clone()) {
+                    affected.push(previous.name);
+                }
+                affected.push(service.name);
+                for name in &affected {
+                    self.reload_instances(registry, mirror, name).await;
+                }
+            }
+            RegistryEvent::Deregistered { id, name } => {
+                mirror.instances.remove(&id);
+                mirror.health.remove(&id);
+                mirror.in_flight.remove(&id);
+                mirror.routable_since.remove(&id);
+                self.breakers.remove(&id);
+                self.reload_instances(registry, mirror, &name).await;
+                affected.push(name);
+            }
+            RegistryEvent::HealthChanged { id, status } => {
+                if let Some(service) = mirror.instances.get(&id) {
+                    affected.push(service.name.clone());
+                }
+                mirror.health.insert(id, status);
+            }
+            RegistryEvent::BreakerChanged { .. } => {}
+        }
+
+        self.rebuild(mirror, affected);
+    }
+
+    /// Put instances whose maintenance window has ended back into their
+    /// pools, without waiting for the registry to clear the window.
+    fn lift_maintenance(&self, mirror: &mut Mirror) {
+        let mut ended: Vec<String> = mirror
+            .instances
+            .values()
+            .filter(|service| service.maintenance.is_some() && !service.in_maintenance())
+            .map(|service| service.name.clone())
+            .collect();
+        ended.sort();
+        ended.dedup();
+        self.rebuild(mirror, ended);
+    }
+
+    fn rebuild(&self, mirror: &mut Mirror, names: Vec<String>) {
+        if names.is_empty() {
+            return;
+        }
+
+        // Only the sync task writes, so a plain load-modify-store is safe.
+        let mut pools = Pools::clone(&self.pools.load());
+        for name in names {
+            if mirror.has_name(&name) {
+                let pool = self.build_pool(mirror, &name, pools.get(&name));
+                pools.insert(name, Arc::new(pool));
+            } else {
+                pools.remove(&name);
+            }
+        }
+        self.pools.store(Arc::new(pools));
+    }
+
+    /// Replace the mirrored instances of `name` with the registry's name
+    /// lookup, served by its `RouteCache`. The registry invalidates the
+    /// name before publishing a change, so the lookup already reflects it.
+    async fn reload_instances(&self, registry: &ServiceRegistry, mirror: &mut Mirror, name: &str) {
+        match registry.get_services_by_name(name).await {
+            Ok(services) => {
+                mirror.instances.retain(|_, service| service.name != name);
+                mirror
+                    .instances
+                    .extend(services.into_iter().map(|service| (service.id.clone(), service)));
+            }
+            Err(e) => tracing::error!("Failed to load instances of {}: {}", name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderMap;
+    use tokio::sync::broadcast;
+
+    use crate::service::Maintenance;
+
+    const BREAKER: &str = r#"
+        window = 10
+        minimum_calls = 1
+        failure_rate_threshold = 50
+        slow_call_duration = 2000
+        slow_call_rate_threshold = 100
+        reset_timeout = 30
+        half_open_max_calls = 1
+    "#;
+
+    fn balancer(locality: Locality) -> LoadBalancer {
+        let config = toml::from_str(r#"strategy = "round_robin""#).unwrap();
+        let breakers = CircuitBreakers::new(toml::from_str(BREAKER).unwrap(), broadcast::channel(16).0);
+        LoadBalancer::new(config, locality, breakers)
+    }
+
+    fn zone(zone: &str) -> Locality {
+        Locality {
+            region: "eu".to_string(),
+            zone: zone.to_string(),
+            ..Locality::default()
+        }
+    }
+
+    fn instance(id: &str) -> Service {
+        let mut service = Service::new("orders".to_string(), "127.0.0.1".to_string(), 8000);
+        service.id = id.to_string();
+        service
+    }
+
+    fn load(balancer: &LoadBalancer, instances: Vec<Service>) -> Mirror {
+        let mut mirror = Mirror {
+            loaded: true,
+            ..Mirror::default()
+        };
+        for service in instances {
+            mirror.instances.insert(service.id.clone(), service);
+        }
+        balancer.rebuild(&mut mirror, vec!["orders".to_string()]);
+        mirror
+    }
+
+    fn pick(balancer: &LoadBalancer) -> Result<String> {
+        let headers = HeaderMap::new();
+        let request = RequestAttributes {
+            headers: &headers,
+            path: "/",
+            client: None,
+        };
+        balancer
+            .get_service("orders", None, &request, &[])
+            .map(|selection| selection.service.id.clone())
+    }
+
+    #[tokio::test]
+    async fn instance_rejoins_when_its_maintenance_ends() {
+        let balancer = balancer(Locality::default());
+        let mut service = instance("a");
+        service.maintenance = Some(Maintenance {
+            reason: "patching".to_string(),
+            expires_at: Some(Utc::now() + chrono::Duration::milliseconds(100)),
+        });
+        let mut mirror = load(&balancer, vec![service]);
+        assert!(matches!(pick(&balancer), Err(Error::NoHealthyInstance(_))));
+
+        let expiry = mirror.next_expiry().expect("window still open");
+        tokio::time::sleep(expiry).await;
+        balancer.lift_maintenance(&mut mirror);
+        assert_eq!(pick(&balancer).unwrap(), "a");
+        assert!(mirror.next_expiry().is_none());
+    }
+
+    #[tokio::test]
+    async fn spills_to_other_zones_when_every_local_breaker_is_open() {
+        let balancer = balancer(zone("a"));
+        let mut local = vec![instance("a1"), instance("a2")];
+        for service in &mut local {
+            service.locality = zone("a");
+        }
+        let mut remote = instance("b1");
+        remote.locality = zone("b");
+        let _mirror = load(&balancer, local.iter().cloned().chain([remote]).collect());
+        assert!(pick(&balancer).unwrap().starts_with('a'));
+
+        for service in &local {
+            balancer.breakers.get(service).acquire().unwrap().record(true);
+        }
+        assert_eq!(pick(&balancer).unwrap(), "b1");
+    }
+}
@@ -1,38 +1,107 @@
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use moka::future::Cache;
+use serde::Serialize;
 
+use crate::config::RouteCacheConfig;
+use crate::discovery::RegistryEvent;
 use crate::service::Service;
 
+/// Registered instances by service name, so name lookups skip a scan of
+/// the store. Entries are dropped as soon as the registry publishes a
+/// change for the name; the TTL only bounds staleness if an event is
+/// ever missed.
+#[derive(Clone)]
 pub struct RouteCache {
     cache: Cache<String, Vec<Service>>,
+    /// Bumped on every invalidation, so a load that raced with one is not
+    /// cached.
+    generation: Arc<AtomicU64>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    invalidations: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteCacheStats {
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+    pub hit_rate: f64,
 }
 
 impl RouteCache {
-    pub fn new() -> Self {
+    pub fn new(config: &RouteCacheConfig) -> Self {
         Self {
             cache: Cache::builder()
-                .time_to_live(Duration::from_secs(30))
-                .time_to_idle(Duration::from_secs(10))
-                .max_capacity(100)
+                .time_to_live(config.ttl())
+                .time_to_idle(config.idle())
+                .max_capacity(config.capacity)
                 .build(),
+            generation: Arc::new(AtomicU64::new(0)),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            invalidations: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<Vec<Service>> {
-        self.cache.get(key).await
+    /// Instances of `name`, from the cache or else from `load`.
+    pub async fn get_or_load<F, E>(&self, name: &str, load: F) -> Result<Vec<Service>, E>
+    where
+        F: FnOnce() -> Result<Vec<Service>, E>,
+    {
+        if let Some(services) = self.cache.get(name).await {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(services);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let generation = self.generation.load(Ordering::Acquire);
+        let services = load()?;
+        if self.generation.load(Ordering::Acquire) == generation {
+            self.cache.insert(name.to_string(), services.clone()).await;
+            // An invalidation may have slipped in just before the insert.
+            if self.generation.load(Ordering::Acquire) != generation {
+                self.cache.invalidate(name).await;
+            }
+        }
+        Ok(services)
     }
 
-    pub async fn insert(&self, key: String, value: Vec<Service>) {
-        self.cache.insert(key, value).await;
+    pub async fn invalidate(&self, name: &str) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+        self.cache.invalidate(name).await;
     }
 
-    pub async fn invalidate(&self, key: &str) {
-        self.cache.invalidate(key).await;
+    /// Drop entries made stale by a registry change.
+    pub async fn apply(&self, event: &RegistryEvent) {
+        match event {
+            RegistryEvent::Registered { service } | RegistryEvent::Updated { service } => {
+                self.invalidate(&service.name).await
+            }
+            RegistryEvent::Deregistered { name, .. } => self.invalidate(name).await,
+            // Health is applied on read, never cached.
+            RegistryEvent::HealthChanged { .. } | RegistryEvent::BreakerChanged { .. } => {}
+        }
     }
-}
 
-impl Default for RouteCache {
-    fn default() -> Self {
-        Self::new()
+    pub async fn stats(&self) -> RouteCacheStats {
+        // Entry counts lag behind until moka's housekeeping has run.
+        self.cache.run_pending_tasks().await;
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        RouteCacheStats {
+            entries: self.cache.entry_count(),
+            hits,
+            misses,
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            hit_rate: if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            },
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,48 +1,552 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
 
+use axum::http::Response;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tower::{BoxError, Layer};
+
+use crate::config::{CircuitBreakerConfig, CircuitBreakerOverride};
+use crate::discovery::RegistryEvent;
+use crate::service::Service;
+
+/// Instance metadata keys under this prefix override breaker settings for
+/// that instance, e.g. `lodestone.breaker.reset_timeout = "10"`.
+pub const BREAKER_KEY_PREFIX: &str = "lodestone.breaker.";
+
+/// Buckets the rolling window is split into; older outcomes fall out one
+/// bucket at a time.
+const WINDOW_BUCKETS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Whether a response that did arrive still counts as a failed call.
+pub trait ClassifyResponse {
+    fn is_failure(&self) -> bool;
+}
+
+impl<B> ClassifyResponse for Response<B> {
+    fn is_failure(&self) -> bool {
+        self.status().is_server_error()
+    }
+}
+
+/// Whether an error from the inner service counts as a failed call.
+/// Errors meaning the call never reached the instance don't.
+pub trait ClassifyError {
+    fn is_failure(&self) -> bool;
+}
+
+/// Returned in place of calling the inner service while the breaker is
+/// open, or half-open with all trial calls in flight.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub id: String,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit breaker for {} is open", self.id)
+    }
+}
+
+/// A breaker's state and what its rolling window currently holds.
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerStatus {
+    pub service: String,
+    pub id: String,
+    pub state: BreakerState,
+    pub calls: u64,
+    pub failures: u64,
+    pub slow_calls: u64,
+    pub failure_rate_threshold: u8,
+    pub slow_call_rate_threshold: u8,
+    pub opened_at: Option<DateTime<Utc>>,
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    epoch: u64,
+    calls: u32,
+    failures: u32,
+    slow: u32,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    opened_at: Option<Instant>,
+    buckets: [Bucket; WINDOW_BUCKETS],
+    trials: u32,
+    trial_successes: u32,
+}
+
+/// Circuit breaker for one upstream instance, over a rolling window of
+/// call outcomes.
+///
+/// Closed, it trips open once the window holds `minimum_calls` and either
+/// the failure rate or the slow-call rate crosses its threshold. After
+/// `reset_timeout` it turns half-open and lets `half_open_max_calls` trial
+/// calls through: any failed or slow trial reopens it, and it closes once
+/// all of them succeed. Every transition is published as a
+/// `RegistryEvent::BreakerChanged`.
+#[derive(Debug, Clone)]
 pub struct CircuitBreaker {
-    failure_threshold: usize,
-    reset_timeout: Duration,
-    failures: AtomicUsize,
-    last_failure: RwLock<Option<Instant>>,
+    service: Arc<str>,
+    id: Arc<str>,
+    config: Arc<CircuitBreakerConfig>,
+    started: Instant,
+    inner: Arc<Mutex<Inner>>,
+    events: broadcast::Sender<RegistryEvent>,
 }
 
 impl CircuitBreaker {
-    pub fn new(failure_threshold: usize, reset_timeout: Duration) -> Self {
+    pub fn new(
+        service: &str,
+        id: &str,
+        config: CircuitBreakerConfig,
+        events: broadcast::Sender<RegistryEvent>,
+    ) -> Self {
         Self {
-            failure_threshold,
-            reset_timeout,
-            failures: AtomicUsize::new(0),
-            last_failure: RwLock::new(None),
+            service: service.into(),
+            id: id.into(),
+            config: Arc::new(config),
+            started: Instant::now(),
+            inner: Arc::new(Mutex::new(Inner {
+                state: BreakerState::Closed,
+                opened_at: None,
+                buckets: [Bucket::default(); WINDOW_BUCKETS],
+                trials: 0,
+                trial_successes: 0,
+            })),
+            events,
         }
     }
 
-    pub async fn record_success(&self) {
-        self.failures.store(0, Ordering::SeqCst);
-        *self.last_failure.write().await = None;
+    pub fn id(&self) -> &str {
+        &self.id
     }
 
-    pub async fn record_failure(&self) -> bool {
-        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
-        *self.last_failure.write().await = Some(Instant::now());
-        failures >= self.failure_threshold
+    /// Whether `acquire` would currently hand out a permit.
+    pub fn allows_calls(&self) -> bool {
+        let inner = self.lock();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => self.reset_elapsed(&inner),
+            BreakerState::HalfOpen => inner.trials < self.config.half_open_max_calls,
+        }
     }
 
-    pub async fn is_open(&self) -> bool {
-        let failures = self.failures.load(Ordering::SeqCst);
-        if failures < self.failure_threshold {
-            return false;
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.lock();
+        let (calls, failures, slow_calls) = self.totals(&inner, self.epoch());
+        BreakerStatus {
+            service: self.service.to_string(),
+            id: self.id.to_string(),
+            state: inner.state,
+            calls,
+            failures,
+            slow_calls,
+            failure_rate_threshold: self.config.failure_rate_threshold,
+            slow_call_rate_threshold: self.config.slow_call_rate_threshold,
+            opened_at: inner
+                .opened_at
+                .filter(|_| inner.state != BreakerState::Closed)
+                .and_then(|opened| chrono::Duration::from_std(opened.elapsed()).ok())
+                .map(|elapsed| Utc::now() - elapsed),
         }
+    }
 
-        if let Some(last_failure) = *self.last_failure.read().await {
-            if last_failure.elapsed() > self.reset_timeout {
-                self.failures.store(0, Ordering::SeqCst);
-                return false;
+    /// Ask to make a call. The permit must be given the call's outcome.
+    pub fn acquire(&self) -> Option<Permit> {
+        let mut inner = self.lock();
+        let trial = match inner.state {
+            BreakerState::Closed => false,
+            BreakerState::Open if self.reset_elapsed(&inner) => {
+                self.transition(&mut inner, BreakerState::HalfOpen);
+                true
             }
+            BreakerState::Open => return None,
+            BreakerState::HalfOpen if inner.trials < self.config.half_open_max_calls => true,
+            BreakerState::HalfOpen => return None,
+        };
+        if trial {
+            inner.trials += 1;
         }
 
-        true
+        Some(Permit {
+            breaker: self.clone(),
+            started: Instant::now(),
+            trial,
+            recorded: false,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn reset_elapsed(&self, inner: &Inner) -> bool {
+        inner
+            .opened_at
+            .map(|opened| opened.elapsed() >= self.config.reset_timeout())
+            .unwrap_or(true)
+    }
+
+    fn bucket_len(&self) -> Duration {
+        (self.config.window() / WINDOW_BUCKETS as u32).max(Duration::from_millis(1))
+    }
+
+    fn epoch(&self) -> u64 {
+        (self.started.elapsed().as_nanos() / self.bucket_len().as_nanos()) as u64
+    }
+
+    fn record(&self, trial: bool, failed: bool, slow: bool) {
+        let mut inner = self.lock();
+        match (inner.state, trial) {
+            (BreakerState::HalfOpen, true) => {
+                if failed || slow {
+                    self.transition(&mut inner, BreakerState::Open);
+                } else {
+                    inner.trial_successes += 1;
+                    if inner.trial_successes >= self.config.half_open_max_calls {
+                        self.transition(&mut inner, BreakerState::Closed);
+                    }
+                }
+            }
+            (BreakerState::Closed, false) => {
+                let epoch = self.epoch();
+                let bucket = &mut inner.buckets[epoch as usize % WINDOW_BUCKETS];
+                if bucket.epoch != epoch {
+                    *bucket = Bucket {
+                        epoch,
+                        ..Bucket::default()
+                    };
+                }
+                bucket.calls += 1;
+                bucket.failures += failed as u32;
+                bucket.slow += slow as u32;
+
+                if self.should_trip(&inner, epoch) {
+                    self.transition(&mut inner, BreakerState::Open);
+                }
+            }
+            // Outcome of a call started before the last transition.
+            _ => {}
+        }
+    }
+
+    fn release_trial(&self) {
+        let mut inner = self.lock();
+        if inner.state == BreakerState::HalfOpen {
+            inner.trials = inner.trials.saturating_sub(1);
+        }
+    }
+
+    /// Calls, failures and slow calls in the window ending at `epoch`.
+    fn totals(&self, inner: &Inner, epoch: u64) -> (u64, u64, u64) {
+        inner
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.epoch + WINDOW_BUCKETS as u64 > epoch)
+            .fold((0, 0, 0), |(calls, failures, slow), bucket| {
+                (
+                    calls + bucket.calls as u64,
+                    failures + bucket.failures as u64,
+                    slow + bucket.slow as u64,
+                )
+            })
+    }
+
+    fn should_trip(&self, inner: &Inner, epoch: u64) -> bool {
+        let (calls, failures, slow) = self.totals(inner, epoch);
+        calls > 0
+            && calls >= self.config.minimum_calls as u64
+            && (failures * 100 >= calls * self.config.failure_rate_threshold as u64
+                || slow * 100 >= calls * self.config.slow_call_rate_threshold as u64)
+    }
+
+    fn transition(&self, inner: &mut Inner, to: BreakerState) {
+        let from = inner.state;
+        inner.state = to;
+        inner.trials = 0;
+        inner.trial_successes = 0;
+        match to {
+            BreakerState::Open => inner.opened_at = Some(Instant::now()),
+            BreakerState::Closed => inner.buckets = [Bucket::default(); WINDOW_BUCKETS],
+            BreakerState::HalfOpen => {}
+        }
+
+        tracing::info!(
+            "Circuit breaker for {} ({}) {:?} -> {:?}",
+            self.id,
+            self.service,
+            from,
+            to
+        );
+        let _ = self.events.send(RegistryEvent::BreakerChanged {
+            service: self.service.to_string(),
+            id: self.id.to_string(),
+            from,
+            to,
+        });
+    }
+}
+
+/// One breaker per upstream instance, keyed by instance id and created on
+/// first use.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakers {
+    config: Arc<CircuitBreakerConfig>,
+    events: broadcast::Sender<RegistryEvent>,
+    breakers: Arc<DashMap<String, CircuitBreaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitBreakerConfig, events: broadcast::Sender<RegistryEvent>) -> Self {
+        Self {
+            config: Arc::new(config),
+            events,
+            breakers: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn get(&self, service: &Service) -> CircuitBreaker {
+        self.breakers
+            .entry(service.id.clone())
+            .or_insert_with(|| {
+                CircuitBreaker::new(
+                    &service.name,
+                    &service.id,
+                    self.config_for(service),
+                    self.events.clone(),
+                )
+            })
+            .clone()
+    }
+
+    /// Instances without a breaker have never failed, so they allow calls.
+    pub fn allows_calls(&self, id: &str) -> bool {
+        self.breakers
+            .get(id)
+            .map(|breaker| breaker.allows_calls())
+            .unwrap_or(true)
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.breakers.remove(id);
+    }
+
+    /// Drop the instance's breaker if its settings have changed, so the
+    /// next call starts a fresh one with the new settings.
+    pub fn refresh(&self, service: &Service) {
+        let config = self.config_for(service);
+        self.breakers
+            .remove_if(&service.id, |_, breaker| *breaker.config != config);
+    }
+
+    /// Global settings, then `[circuit_breaker.services.<name>]`, then the
+    /// instance's own metadata.
+    fn config_for(&self, service: &Service) -> CircuitBreakerConfig {
+        let mut config = CircuitBreakerConfig {
+            services: Default::default(),
+            ..CircuitBreakerConfig::clone(&self.config)
+        };
+        if let Some(overrides) = self.config.services.get(&service.name) {
+            overrides.apply_to(&mut config);
+        }
+        metadata_override(service).apply_to(&mut config);
+        config
+    }
+}
+
+fn metadata_override(service: &Service) -> CircuitBreakerOverride {
+    fn field<T: std::str::FromStr>(service: &Service, name: &str) -> Option<T> {
+        let key = format!("{}{}", BREAKER_KEY_PREFIX, name);
+        let value = service.metadata.get(&key)?;
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            tracing::warn!("Ignoring {} for {}: invalid value {}", key, service.id, value);
+        }
+        parsed
+    }
+
+    CircuitBreakerOverride {
+        window: field(service, "window"),
+        minimum_calls: field(service, "minimum_calls"),
+        failure_rate_threshold: field(service, "failure_rate_threshold"),
+        slow_call_duration: field(service, "slow_call_duration"),
+        slow_call_rate_threshold: field(service, "slow_call_rate_threshold"),
+        reset_timeout: field(service, "reset_timeout"),
+        half_open_max_calls: field(service, "half_open_max_calls"),
+    }
+}
+
+/// Permission to make one call. Dropping it unrecorded, e.g. when the
+/// call is cancelled, gives a half-open trial slot back.
+#[derive(Debug)]
+pub struct Permit {
+    breaker: CircuitBreaker,
+    started: Instant,
+    trial: bool,
+    recorded: bool,
+}
+
+impl Permit {
+    pub fn record(mut self, failed: bool) {
+        let slow = self.started.elapsed() >= self.breaker.config.slow_call_duration();
+        self.breaker.record(self.trial, failed, slow);
+        self.recorded = true;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.trial && !self.recorded {
+            self.breaker.release_trial();
+        }
+    }
+}
+
+/// Guards a service with a `CircuitBreaker`. Errors from the inner service
+/// and responses it classifies as failures count against the breaker.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerLayer {
+    breaker: CircuitBreaker,
+}
+
+impl CircuitBreakerLayer {
+    pub fn new(breaker: CircuitBreaker) -> Self {
+        Self { breaker }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreakerService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CircuitBreakerService {
+            inner,
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerService<S> {
+    inner: S,
+    breaker: CircuitBreaker,
+}
+
+impl<S, Request> tower::Service<Request> for CircuitBreakerService<S>
+where
+    S: tower::Service<Request>,
+    S::Response: ClassifyResponse,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let Some(permit) = self.breaker.acquire() else {
+            let id = self.breaker.id().to_string();
+            return Box::pin(async move { Err(CircuitOpen { id }.into()) });
+        };
+
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let result = future.await.map_err(Into::into);
+            permit.record(match &result {
+                Ok(response) => response.is_failure(),
+                Err(e) => e.is_failure(),
+            });
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::{service_fn, ServiceExt};
+
+    use crate::proxy::UpstreamError;
+
+    const CONFIG: &str = r#"
+        window = 10
+        minimum_calls = 1
+        failure_rate_threshold = 50
+        slow_call_duration = 2000
+        slow_call_rate_threshold = 100
+        reset_timeout = 30
+        half_open_max_calls = 1
+    "#;
+
+    fn breakers() -> CircuitBreakers {
+        CircuitBreakers::new(toml::from_str(CONFIG).unwrap(), broadcast::channel(16).0)
+    }
+
+    fn instance() -> Service {
+        Service::new("orders".to_string(), "127.0.0.1".to_string(), 8000)
+    }
+
+    async fn fail_with(breaker: &CircuitBreaker, error: fn() -> UpstreamError) {
+        let service = service_fn(move |()| async move { Err::<Response<()>, _>(error()) });
+        let result = CircuitBreakerLayer::new(breaker.clone()).layer(service).oneshot(()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn pool_exhaustion_is_not_a_failure() {
+        let breaker = breakers().get(&instance());
+        fail_with(&breaker, || UpstreamError::PoolExhausted("orders".to_string())).await;
+        assert_eq!(breaker.status().failures, 0);
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+
+        fail_with(&breaker, || {
+            UpstreamError::Connect("orders".to_string(), std::io::ErrorKind::ConnectionRefused.into())
+        })
+        .await;
+        assert_eq!(breaker.status().failures, 1);
+        assert_eq!(breaker.status().state, BreakerState::Open);
+    }
+
+    #[test]
+    fn refresh_applies_changed_metadata() {
+        let breakers = breakers();
+        let mut service = instance();
+        breakers.get(&service).acquire().unwrap().record(true);
+        assert!(!breakers.allows_calls(&service.id));
+
+        // Unchanged settings keep the breaker and its state.
+        breakers.refresh(&service);
+        assert!(!breakers.allows_calls(&service.id));
+
+        service
+            .metadata
+            .insert(format!("{}reset_timeout", BREAKER_KEY_PREFIX), "5".to_string());
+        breakers.refresh(&service);
+        assert!(breakers.allows_calls(&service.id));
+        assert_eq!(breakers.get(&service).config.reset_timeout, 5);
     }
 }
@@ -0,0 +1,356 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use axum::http::{header, HeaderMap};
+use serde::Deserialize;
+
+use crate::cluster::hash;
+use crate::prelude::*;
+use super::strategy::{pick_filtered, BalancingStrategy, Endpoint};
+
+/// Instance metadata key naming the request attribute to hash on.
+pub const HASH_ON_KEY: &str = "lodestone.hash_on";
+
+/// Instance metadata key naming the cookie used for sticky sessions.
+pub const STICKY_COOKIE_KEY: &str = "lodestone.sticky_cookie";
+
+/// Ring points per unit of instance weight, while the ring stays within
+/// `MAX_RING_POINTS`.
+const RING_POINTS_PER_WEIGHT: u64 = 100;
+/// Beyond this, points per unit of weight are scaled down so large
+/// weights or pools don't make every rebuild expensive.
+const MAX_RING_POINTS: u64 = 100_000;
+/// Prime Maglev lookup table size; large enough for a few hundred backends.
+const MAGLEV_TABLE_SIZE: usize = 65537;
+
+/// Request attribute that hash-based strategies key on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum HashOn {
+    Header(String),
+    Cookie(String),
+    PathSegment(usize),
+    SourceIp,
+}
+
+impl FromStr for HashOn {
+    type Err = Error;
+
+    /// Parses `header:<name>`, `cookie:<name>`, `path_segment:<index>` or
+    /// `source_ip`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Config(format!("invalid hash key: {}", s));
+        match s.split_once(':') {
+            Some(("header", name)) if !name.is_empty() => Ok(HashOn::Header(name.to_ascii_lowercase())),
+            Some(("cookie", name)) if !name.is_empty() => Ok(HashOn::Cookie(name.to_string())),
+            Some(("path_segment", index)) => index.parse().map(HashOn::PathSegment).map_err(|_| invalid()),
+            None if s == "source_ip" => Ok(HashOn::SourceIp),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for HashOn {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+/// The parts of an incoming request the balancer may key on.
+pub struct RequestAttributes<'a> {
+    pub headers: &'a HeaderMap,
+    pub path: &'a str,
+    pub client: Option<IpAddr>,
+}
+
+impl RequestAttributes<'_> {
+    pub fn hash(&self, on: &HashOn) -> Option<u64> {
+        match on {
+            HashOn::Header(name) => self
+                .headers
+                .get(name.as_str())
+                .map(|value| hash(value.as_bytes())),
+            HashOn::Cookie(name) => self.cookie(name).map(|value| hash(value.as_bytes())),
+            HashOn::PathSegment(index) => self
+                .path
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .nth(*index)
+                .map(|segment| hash(segment.as_bytes())),
+            HashOn::SourceIp => self.client.map(|ip| hash(ip.to_string().as_bytes())),
+        }
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Identifies an endpoint set so cached lookup structures can tell when
+/// they are stale.
+fn fingerprint(endpoints: &[Endpoint]) -> u64 {
+    endpoints.iter().fold(0u64, |acc, endpoint| {
+        acc.rotate_left(5) ^ hash(format!("{}:{}", endpoint.service.id, endpoint.weight()).as_bytes())
+    })
+}
+
+struct Ring {
+    fingerprint: u64,
+    points: Vec<(u64, usize)>,
+}
+
+/// Consistent hashing on a ring with weight-proportional virtual nodes.
+/// Adding or removing an instance only moves the keys adjacent to its
+/// points.
+#[derive(Default)]
+pub struct RingHash {
+    ring: ArcSwapOption<Ring>,
+}
+
+impl std::fmt::Debug for RingHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RingHash").finish_non_exhaustive()
+    }
+}
+
+impl RingHash {
+    fn ring(&self, endpoints: &[Endpoint]) -> Arc<Ring> {
+        let fingerprint = fingerprint(endpoints);
+        if let Some(ring) = self.ring.load_full().filter(|ring| ring.fingerprint == fingerprint) {
+            return ring;
+        }
+
+        let total_weight: u64 = endpoints.iter().map(|endpoint| endpoint.weight() as u64).sum();
+        let per_weight = (MAX_RING_POINTS / total_weight.max(1)).clamp(1, RING_POINTS_PER_WEIGHT);
+        let mut points: Vec<(u64, usize)> = endpoints
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, endpoint)| {
+                let id = &endpoint.service.id;
+                (0..endpoint.weight() as u64 * per_weight)
+                    .map(move |i| (hash(format!("{}-{}", id, i).as_bytes()), idx))
+            })
+            .collect();
+        points.sort_unstable();
+
+        let ring = Arc::new(Ring { fingerprint, points });
+        self.ring.store(Some(ring.clone()));
+        ring
+    }
+}
+
+impl BalancingStrategy for RingHash {
+    fn pick<'a>(&self, endpoints: &'a [Endpoint], key: Option<u64>) -> Option<&'a Endpoint> {
+        let Some(key) = key else {
+            return super::strategy::Random.pick(endpoints, None);
+        };
+        if endpoints.is_empty() {
+            return None;
+        }
+
+        let ring = self.ring(endpoints);
+        let idx = ring.points.partition_point(|(point, _)| *point < key);
+        let (_, endpoint) = ring.points.get(idx).or_else(|| ring.points.first())?;
+        endpoints.get(*endpoint)
+    }
+
+    fn pick_eligible(
+        &self,
+        endpoints: &[Endpoint],
+        eligible: &dyn Fn(&Endpoint) -> bool,
+        key: Option<u64>,
+    ) -> Option<Endpoint> {
+        let Some(key) = key else {
+            return pick_filtered(self, endpoints, eligible, None);
+        };
+        if !endpoints.iter().any(eligible) {
+            return None;
+        }
+
+        // Walk clockwise past ineligible endpoints' points.
+        let ring = self.ring(endpoints);
+        let start = ring.points.partition_point(|(point, _)| *point < key);
+        let (before, after) = ring.points.split_at(start);
+        after
+            .iter()
+            .chain(before)
+            .map(|(_, idx)| &endpoints[*idx])
+            .find(|endpoint| eligible(endpoint))
+            .cloned()
+    }
+}
+
+struct Table {
+    fingerprint: u64,
+    entries: Vec<usize>,
+}
+
+/// Maglev hashing: a fixed-size lookup table filled from per-instance
+/// permutations, giving weight-proportional spread and minimal disruption
+/// on change.
+#[derive(Default)]
+pub struct Maglev {
+    table: ArcSwapOption<Table>,
+}
+
+impl std::fmt::Debug for Maglev {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Maglev").finish_non_exhaustive()
+    }
+}
+
+impl Maglev {
+    fn table(&self, endpoints: &[Endpoint]) -> Arc<Table> {
+        let fingerprint = fingerprint(endpoints);
+        if let Some(table) = self.table.load_full().filter(|table| table.fingerprint == fingerprint) {
+            return table;
+        }
+
+        let size = MAGLEV_TABLE_SIZE;
+        let permutations: Vec<(usize, usize)> = endpoints
+            .iter()
+            .map(|endpoint| {
+                let id = endpoint.service.id.as_bytes();
+                let offset = hash(id) as usize % size;
+                let skip = hash(&[id, b"#skip"].concat()) as usize % (size - 1) + 1;
+                (offset, skip)
+            })
+            .collect();
+
+        let mut entries = vec![usize::MAX; size];
+        let mut next = vec![0usize; endpoints.len()];
+        let mut filled = 0;
+        'fill: loop {
+            // Each round an instance claims as many slots as its weight.
+            for (idx, (offset, skip)) in permutations.iter().enumerate() {
+                for _ in 0..endpoints[idx].weight() {
+                    let mut slot = (offset + next[idx] * skip) % size;
+                    while entries[slot] != usize::MAX {
+                        next[idx] += 1;
+                        slot = (offset + next[idx] * skip) % size;
+                    }
+                    entries[slot] = idx;
+                    next[idx] += 1;
+                    filled += 1;
+                    if filled == size {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+
+        let table = Arc::new(Table { fingerprint, entries });
+        self.table.store(Some(table.clone()));
+        table
+    }
+}
+
+impl BalancingStrategy for Maglev {
+    fn pick<'a>(&self, endpoints: &'a [Endpoint], key: Option<u64>) -> Option<&'a Endpoint> {
+        let Some(key) = key else {
+            return super::strategy::Random.pick(endpoints, None);
+        };
+        if endpoints.is_empty() {
+            return None;
+        }
+
+        let table = self.table(endpoints);
+        endpoints.get(table.entries[key as usize % table.entries.len()])
+    }
+
+    fn pick_eligible(
+        &self,
+        endpoints: &[Endpoint],
+        eligible: &dyn Fn(&Endpoint) -> bool,
+        key: Option<u64>,
+    ) -> Option<Endpoint> {
+        let Some(key) = key else {
+            return pick_filtered(self, endpoints, eligible, None);
+        };
+        if !endpoints.iter().any(eligible) {
+            return None;
+        }
+
+        // Move on through the table past ineligible endpoints' slots.
+        let table = self.table(endpoints);
+        let (before, after) = table.entries.split_at(key as usize % table.entries.len());
+        after
+            .iter()
+            .chain(before)
+            .map(|idx| &endpoints[*idx])
+            .find(|endpoint| eligible(endpoint))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::Service;
+
+    const INSTANCES: usize = 10;
+    const KEYS: u64 = 10_000;
+
+    fn endpoints(count: usize) -> Vec<Endpoint> {
+        (0..count)
+            .map(|i| {
+                let mut service = Service::new("orders".into(), "127.0.0.1".into(), 7000 + i as u16);
+                service.id = format!("orders-{}", i);
+                Endpoint { service, in_flight: Default::default(), ramp: None }
+            })
+            .collect()
+    }
+
+    fn assignments(strategy: &dyn BalancingStrategy, endpoints: &[Endpoint], skip: &str) -> Vec<String> {
+        (0..KEYS)
+            .map(|key| {
+                let key = hash(&key.to_be_bytes());
+                let eligible = |e: &Endpoint| e.service.id != skip;
+                strategy.pick_eligible(endpoints, &eligible, Some(key)).unwrap().service.id
+            })
+            .collect()
+    }
+
+    /// Taking one instance out moves its own keys and leaves the rest.
+    fn remaps_only_the_missing_instance(strategy: impl Fn() -> Box<dyn BalancingStrategy>) {
+        let all = endpoints(INSTANCES);
+        let gone = "orders-3";
+        let before = assignments(strategy().as_ref(), &all, "");
+
+        // Skipped on lookup, as retries and open breakers do.
+        let skipped = assignments(strategy().as_ref(), &all, gone);
+        // Rebuilt without it, as when it deregisters.
+        let rest: Vec<Endpoint> = all.iter().filter(|e| e.service.id != gone).cloned().collect();
+        let rebuilt = assignments(strategy().as_ref(), &rest, "");
+
+        let owned = before.iter().filter(|id| *id == gone).count();
+        let expected = KEYS as usize / INSTANCES;
+        assert!(owned.abs_diff(expected) < expected / 2, "{} keys on one of {}", owned, INSTANCES);
+        for (before, after) in before.iter().zip(&skipped) {
+            assert!(before == after || before == gone);
+        }
+        let moved = before.iter().zip(&rebuilt).filter(|(before, after)| before != after).count();
+        assert!(moved < owned + owned / 5, "{} keys moved, {} expected", moved, owned);
+    }
+
+    #[test]
+    fn ring_hash_remaps_about_one_nth_of_keys() {
+        remaps_only_the_missing_instance(|| Box::new(RingHash::default()));
+    }
+
+    #[test]
+    fn maglev_remaps_about_one_nth_of_keys() {
+        remaps_only_the_missing_instance(|| Box::new(Maglev::default()));
+    }
+}
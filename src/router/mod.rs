@@ -1,11 +1,15 @@
 mod routes;
 mod balancer;
+mod strategy;
+mod hashing;
 mod circuit_breaker;
 mod cache;
-mod websocket;
 
 pub use routes::Router;
-pub use balancer::LoadBalancer;
-pub use circuit_breaker::CircuitBreaker;
-pub use cache::RouteCache;
-pub use websocket::WebSocketHandler;
\ No newline at end of file
+pub use balancer::{LoadBalancer, Selection};
+pub use strategy::StrategyKind;
+pub use hashing::{HashOn, RequestAttributes};
+pub use circuit_breaker::{
+    BreakerState, BreakerStatus, CircuitBreakerLayer, CircuitBreakers, CircuitOpen, ClassifyError,
+};
+pub use cache::{RouteCache, RouteCacheStats};
@@ -1,30 +1,102 @@
 use axum::{
     Router as AxumRouter,
-    routing::{get, post, delete},
-    extract::{State, Path},
+    routing::{get, post, put, delete},
+    extract::{State, Path, Query},
     Json,
-    response::IntoResponse,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
     http::StatusCode,
 };
+use axum::body::Bytes;
+use futures::stream::{self, Stream};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast::error::RecvError, RwLock};
+use super::{BreakerStatus, CircuitBreakers, RouteCacheStats};
 use crate::{
-    discovery::ServiceRegistry, error::Error, service::Service
+    cluster::{HealthReport, MemberStatus, Membership},
+    consensus::{self, RaftNode},
+    discovery::ServiceRegistry, error::Error,
+    health::{HealthStatus, NodeHealth, NodeHealthReport, ServiceGroup},
+    proxy::{PoolStats, Route, RouteTable, UpstreamClient},
+    service::{Maintenance, Service}
 };
 
+#[derive(Debug, Deserialize)]
+struct ServicesQuery {
+    name: Option<String>,
+    #[serde(default)]
+    healthy: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthQuery {
+    #[serde(default)]
+    aggregate: bool,
+}
+
 pub struct Router {
     registry: Arc<RwLock<ServiceRegistry>>,
+    raft: Arc<RwLock<RaftNode>>,
+    membership: Membership,
+    node_health: NodeHealth,
+    breakers: CircuitBreakers,
+    routes: RouteTable,
+    upstream: UpstreamClient,
 }
 
 impl Router {
-    pub fn new(registry: Arc<RwLock<ServiceRegistry>>) -> AxumRouter {
-        let shared_state = Arc::new(Self { registry });
+    pub fn new(
+        registry: Arc<RwLock<ServiceRegistry>>,
+        raft: Arc<RwLock<RaftNode>>,
+        membership: Membership,
+        node_health: NodeHealth,
+        breakers: CircuitBreakers,
+        routes: RouteTable,
+        upstream: UpstreamClient,
+    ) -> AxumRouter {
+        let shared_state = Arc::new(Self {
+            registry,
+            raft,
+            membership,
+            node_health,
+            breakers,
+            routes,
+            upstream,
+        });
 
         AxumRouter::new()
+            .route("/livez", get(Self::livez))
+            .route("/readyz", get(Self::readyz))
+            .route("/healthz", get(Self::healthz))
             .route("/services", post(Self::register_service))
             .route("/services", get(Self::list_services))  // Add this line
             .route("/services/:id", get(Self::get_service))
             .route("/services/:id", delete(Self::deregister_service))
+            .route("/services/watch", get(Self::watch_services))
+            .route("/services/:id/health", get(Self::service_health))
+            .route("/services/:id/maintenance", put(Self::enter_maintenance))
+            .route("/services/:id/maintenance", delete(Self::exit_maintenance))
+            .route("/services/:id/drain", put(Self::drain_service))
+            .route("/services/:id/drain", delete(Self::undrain_service))
+            .route("/services/:id/breaker", get(Self::breaker_status))
+            .route("/groups", get(Self::list_groups))
+            .route("/groups/:name", put(Self::set_group))
+            .route("/groups/:name", get(Self::get_group))
+            .route("/groups/:name", delete(Self::delete_group))
+            .route("/routes", get(Self::list_routes))
+            .route("/routes/:name", put(Self::set_route))
+            .route("/routes/:name", get(Self::get_route))
+            .route("/routes/:name", delete(Self::delete_route))
+            .route("/routes/:name/weights", put(Self::set_route_weights))
+            .route("/cache/stats", get(Self::cache_stats))
+            .route("/pools", get(Self::pool_stats))
+            .route("/cluster/members", get(Self::cluster_members))
+            .route("/cluster/health", post(Self::receive_health_report))
+            .route("/raft/message", post(Self::receive_raft_message))
             .with_state(shared_state)
     }
 
@@ -52,12 +124,261 @@ impl Router {
         Ok(StatusCode::NO_CONTENT)
     }
 
+    /// Instance health by id, or with `?aggregate=true` the service-level
+    /// health of the service name given in the path.
+    async fn service_health(
+        State(state): State<Arc<Router>>,
+        Path(id): Path<String>,
+        Query(query): Query<HealthQuery>,
+    ) -> Result<axum::response::Response, Error> {
+        let registry = state.registry.read().await;
+        if query.aggregate {
+            return Ok(Json(registry.aggregate_health(&id).await?).into_response());
+        }
+
+        let service = registry
+            .get_service(&id)
+            .await?
+            .ok_or(Error::ServiceNotFound(id))?;
+        Ok(Json(registry.health_of(&service)).into_response())
+    }
+
+    async fn breaker_status(
+        State(state): State<Arc<Router>>,
+        Path(id): Path<String>,
+    ) -> Result<Json<BreakerStatus>, Error> {
+        let service = state
+            .registry
+            .read()
+            .await
+            .get_service(&id)
+            .await?
+            .ok_or(Error::ServiceNotFound(id))?;
+        Ok(Json(state.breakers.get(&service).status()))
+    }
+
+    async fn set_group(
+        State(state): State<Arc<Router>>,
+        Path(name): Path<String>,
+        Json(mut group): Json<ServiceGroup>,
+    ) -> Result<Json<ServiceGroup>, Error> {
+        if group.min_healthy_percent > 100 {
+            return Err(Error::BadRequest("min_healthy_percent must be at most 100".to_string()));
+        }
+        group.name = name;
+        state.registry.read().await.set_group(group.clone()).await?;
+        Ok(Json(group))
+    }
+
+    async fn get_group(
+        State(state): State<Arc<Router>>,
+        Path(name): Path<String>,
+    ) -> Result<Json<ServiceGroup>, Error> {
+        let group = state.registry.read().await.get_group(&name).await?;
+        Ok(Json(group.ok_or(Error::ServiceNotFound(name))?))
+    }
+
+    async fn delete_group(
+        State(state): State<Arc<Router>>,
+        Path(name): Path<String>,
+    ) -> Result<StatusCode, Error> {
+        state.registry.read().await.delete_group(&name).await?;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    async fn list_groups(
+        State(state): State<Arc<Router>>,
+    ) -> Result<Json<Vec<ServiceGroup>>, Error> {
+        let groups = state.registry.read().await.list_groups().await?;
+        Ok(Json(groups))
+    }
+
+    async fn set_route(
+        State(state): State<Arc<Router>>,
+        Path(name): Path<String>,
+        Json(mut route): Json<Route>,
+    ) -> Result<Json<Route>, Error> {
+        route.name = name;
+        state.routes.put(&state.raft, route.clone()).await?;
+        Ok(Json(route))
+    }
+
+    async fn get_route(
+        State(state): State<Arc<Router>>,
+        Path(name): Path<String>,
+    ) -> Result<Json<Route>, Error> {
+        let route = state.routes.get(&name);
+        Ok(Json(route.ok_or(Error::ServiceNotFound(name))?))
+    }
+
+    /// Shift traffic between a route's backends, e.g. `{"v1": 90, "v2": 10}`.
+    async fn set_route_weights(
+        State(state): State<Arc<Router>>,
+        Path(name): Path<String>,
+        Json(weights): Json<HashMap<String, u32>>,
+    ) -> Result<Json<Route>, Error> {
+        Ok(Json(state.routes.set_weights(&state.raft, &name, weights).await?))
+    }
+
+    async fn delete_route(
+        State(state): State<Arc<Router>>,
+        Path(name): Path<String>,
+    ) -> Result<StatusCode, Error> {
+        state.routes.delete(&state.raft, &name).await?;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Routes in the order the proxy evaluates them.
+    async fn list_routes(State(state): State<Arc<Router>>) -> Json<Vec<Route>> {
+        Json(state.routes.list())
+    }
+
+    async fn enter_maintenance(
+        State(state): State<Arc<Router>>,
+        Path(id): Path<String>,
+        Json(maintenance): Json<Maintenance>,
+    ) -> Result<Json<Service>, Error> {
+        let service = state.registry.read().await.set_maintenance(&id, Some(maintenance)).await?;
+        Ok(Json(service))
+    }
+
+    async fn exit_maintenance(
+        State(state): State<Arc<Router>>,
+        Path(id): Path<String>,
+    ) -> Result<Json<Service>, Error> {
+        let service = state.registry.read().await.set_maintenance(&id, None).await?;
+        Ok(Json(service))
+    }
+
+    async fn drain_service(
+        State(state): State<Arc<Router>>,
+        Path(id): Path<String>,
+    ) -> Result<Json<Service>, Error> {
+        let service = state.registry.read().await.set_draining(&id, true).await?;
+        Ok(Json(service))
+    }
+
+    async fn undrain_service(
+        State(state): State<Arc<Router>>,
+        Path(id): Path<String>,
+    ) -> Result<Json<Service>, Error> {
+        let service = state.registry.read().await.set_draining(&id, false).await?;
+        Ok(Json(service))
+    }
+
+    /// Server-sent event stream of registry changes, including health,
+    /// maintenance and drain transitions.
+    async fn watch_services(
+        State(state): State<Arc<Router>>,
+    ) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+        let events = state.registry.read().await.subscribe();
+
+        let stream = stream::unfold(events, |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((Event::default().json_data(&event), events)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Watch stream lagged, skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Sse::new(stream).keep_alive(KeepAlive::default())
+    }
+
+    async fn livez(State(state): State<Arc<Router>>) -> (StatusCode, Json<NodeHealthReport>) {
+        Self::node_health_response(state.node_health.liveness())
+    }
+
+    async fn readyz(State(state): State<Arc<Router>>) -> (StatusCode, Json<NodeHealthReport>) {
+        Self::node_health_response(state.node_health.readiness().await)
+    }
+
+    async fn healthz(State(state): State<Arc<Router>>) -> (StatusCode, Json<NodeHealthReport>) {
+        Self::node_health_response(state.node_health.health().await)
+    }
+
+    fn node_health_response(report: NodeHealthReport) -> (StatusCode, Json<NodeHealthReport>) {
+        let status = match report.status {
+            HealthStatus::Healthy => StatusCode::OK,
+            _ => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, Json(report))
+    }
+
+    async fn cluster_members(
+        State(state): State<Arc<Router>>,
+    ) -> Json<Vec<MemberStatus>> {
+        Json(state.membership.members())
+    }
+
+    async fn receive_health_report(
+        State(state): State<Arc<Router>>,
+        Json(report): Json<HealthReport>,
+    ) -> StatusCode {
+        state.membership.heartbeat(report.node_id);
+
+        // Only instances registered here; the read lock keeps them from
+        // being deregistered while their results are recorded.
+        let registry = state.registry.read().await;
+        let registered: HashSet<String> = match registry.list_services().await {
+            Ok(services) => services.into_iter().map(|service| service.id).collect(),
+            Err(e) => {
+                tracing::warn!("Failed to list services for health report: {}", e);
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+        };
+        let checks: Vec<_> = report
+            .checks
+            .into_iter()
+            .filter(|(service_id, _)| registered.contains(service_id))
+            .collect();
+        state.membership.record_reports(
+            report.node_id,
+            checks.iter().map(|(service_id, _)| service_id.as_str()),
+        );
+
+        let health = registry.health();
+        for (service_id, check) in checks {
+            health.record(&service_id, check);
+        }
+        StatusCode::NO_CONTENT
+    }
+
+    /// A protobuf-encoded Raft message from a peer.
+    async fn receive_raft_message(
+        State(state): State<Arc<Router>>,
+        body: Bytes,
+    ) -> Result<StatusCode, Error> {
+        let message = consensus::decode(&body)?;
+        state.raft.write().await.step(message)?;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Every instance, or with `?name=` the instances of one service;
+    /// `&healthy=true` leaves out those not taking traffic.
     async fn list_services(
         State(state): State<Arc<Router>>,
+        Query(query): Query<ServicesQuery>,
     ) -> Result<Json<Vec<Service>>, Error> {
-        let services = state.registry.read().await.list_services().await?;
+        let registry = state.registry.read().await;
+        let services = match query.name {
+            Some(name) if query.healthy => registry.get_healthy_services_by_name(&name).await?,
+            Some(name) => registry.get_services_by_name(&name).await?,
+            None => registry.list_services().await?,
+        };
         Ok(Json(services))
     }
+
+    async fn cache_stats(State(state): State<Arc<Router>>) -> Json<RouteCacheStats> {
+        Json(state.registry.read().await.cache().stats().await)
+    }
+
+    async fn pool_stats(State(state): State<Arc<Router>>) -> Json<Vec<PoolStats>> {
+        Json(state.upstream.pool_stats())
+    }
 }
 
 // Implement IntoResponse for Error to properly handle errors
@@ -65,6 +386,12 @@ impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         let status = match self {
             Error::ServiceNotFound(_) => StatusCode::NOT_FOUND,
+            Error::NoHealthyInstance(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Error::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::TooManyConnections(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
             Error::BadRequest(_) => StatusCode::BAD_REQUEST,
             Error::Auth(_) => StatusCode::UNAUTHORIZED,
             Error::RateLimit => StatusCode::TOO_MANY_REQUESTS,
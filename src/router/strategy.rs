@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::config::SlowStartCurve;
+use crate::prelude::*;
+use crate::service::Service;
+use super::hashing::{Maglev, RingHash};
+
+/// Instance metadata key naming the strategy for the instance's service.
+pub const STRATEGY_KEY: &str = "lodestone.lb_strategy";
+
+/// Weight ramp for an instance that entered rotation at `since`.
+#[derive(Debug, Clone, Copy)]
+pub struct Ramp {
+    pub since: Instant,
+    pub window: Duration,
+    pub curve: SlowStartCurve,
+    pub min_fraction: f64,
+}
+
+impl Ramp {
+    /// Share of full weight the instance gets right now.
+    fn fraction(&self) -> f64 {
+        let progress = self.since.elapsed().as_secs_f64() / self.window.as_secs_f64();
+        if progress >= 1.0 {
+            return 1.0;
+        }
+        let fraction = match self.curve {
+            SlowStartCurve::Linear => progress,
+            // Weight doubles over each quarter of the window.
+            SlowStartCurve::Exponential => (2f64.powf(4.0 * progress) - 1.0) / 15.0,
+        };
+        fraction.max(self.min_fraction)
+    }
+}
+
+/// A routable instance together with its live request count.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub service: Service,
+    pub(super) in_flight: Arc<AtomicUsize>,
+    pub(super) ramp: Option<Ramp>,
+}
+
+impl Endpoint {
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Configured weight, for strategies whose layout depends on it.
+    pub fn weight(&self) -> u32 {
+        self.service.weight.max(1)
+    }
+
+    /// Weight after slow start, for strategies that decide per request.
+    pub fn effective_weight(&self) -> f64 {
+        let weight = self.weight() as f64;
+        match self.ramp {
+            Some(ramp) => weight * ramp.fraction(),
+            None => weight,
+        }
+    }
+
+    /// Outstanding requests relative to weight; lower means more headroom.
+    fn load(&self) -> f64 {
+        (self.in_flight() + 1) as f64 / self.effective_weight()
+    }
+}
+
+pub trait BalancingStrategy: Send + Sync + fmt::Debug {
+    /// Choose one of `endpoints`. `key` is the request hash for
+    /// affinity-based strategies; others ignore it.
+    fn pick<'a>(&self, endpoints: &'a [Endpoint], key: Option<u64>) -> Option<&'a Endpoint>;
+
+    /// Choose one of the `endpoints` that are `eligible`. Strategies with a
+    /// lookup table lay out every endpoint and skip the ineligible ones,
+    /// so retries and open breakers don't reshuffle other keys.
+    fn pick_eligible(
+        &self,
+        endpoints: &[Endpoint],
+        eligible: &dyn Fn(&Endpoint) -> bool,
+        key: Option<u64>,
+    ) -> Option<Endpoint> {
+        pick_filtered(self, endpoints, eligible, key)
+    }
+}
+
+/// Pick from the eligible `endpoints` alone.
+pub fn pick_filtered<S: BalancingStrategy + ?Sized>(
+    strategy: &S,
+    endpoints: &[Endpoint],
+    eligible: &dyn Fn(&Endpoint) -> bool,
+    key: Option<u64>,
+) -> Option<Endpoint> {
+    if endpoints.iter().all(eligible) {
+        return strategy.pick(endpoints, key).cloned();
+    }
+    let available: Vec<Endpoint> = endpoints.iter().filter(|e| eligible(e)).cloned().collect();
+    strategy.pick(&available, key).cloned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    Random,
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastRequest,
+    PowerOfTwoChoices,
+    RingHash,
+    Maglev,
+}
+
+impl StrategyKind {
+    pub fn build(self) -> Arc<dyn BalancingStrategy> {
+        match self {
+            StrategyKind::Random => Arc::new(Random),
+            StrategyKind::RoundRobin => Arc::new(RoundRobin::default()),
+            StrategyKind::WeightedRoundRobin => Arc::new(WeightedRoundRobin::default()),
+            StrategyKind::LeastRequest => Arc::new(LeastRequest),
+            StrategyKind::PowerOfTwoChoices => Arc::new(PowerOfTwoChoices),
+            StrategyKind::RingHash => Arc::new(RingHash::default()),
+            StrategyKind::Maglev => Arc::new(Maglev::default()),
+        }
+    }
+
+    pub fn uses_key(self) -> bool {
+        matches!(self, StrategyKind::RingHash | StrategyKind::Maglev)
+    }
+}
+
+impl FromStr for StrategyKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random" => Ok(StrategyKind::Random),
+            "round_robin" => Ok(StrategyKind::RoundRobin),
+            "weighted_round_robin" => Ok(StrategyKind::WeightedRoundRobin),
+            "least_request" => Ok(StrategyKind::LeastRequest),
+            "power_of_two_choices" => Ok(StrategyKind::PowerOfTwoChoices),
+            "ring_hash" => Ok(StrategyKind::RingHash),
+            "maglev" => Ok(StrategyKind::Maglev),
+            other => Err(Error::Config(format!("unknown balancing strategy: {}", other))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Random;
+
+impl BalancingStrategy for Random {
+    fn pick<'a>(&self, endpoints: &'a [Endpoint], _key: Option<u64>) -> Option<&'a Endpoint> {
+        endpoints
+            .choose_weighted(&mut rand::thread_rng(), Endpoint::effective_weight)
+            .ok()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl BalancingStrategy for RoundRobin {
+    fn pick<'a>(&self, endpoints: &'a [Endpoint], _key: Option<u64>) -> Option<&'a Endpoint> {
+        if endpoints.is_empty() {
+            return None;
+        }
+        let idx = self.next.fetch_add(1, Ordering::Relaxed);
+        endpoints.get(idx % endpoints.len())
+    }
+}
+
+/// Smooth weighted round-robin (as in nginx): interleaves instances in
+/// proportion to their weight instead of sending bursts to the heaviest.
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobin {
+    current: Mutex<HashMap<String, f64>>,
+}
+
+impl BalancingStrategy for WeightedRoundRobin {
+    fn pick<'a>(&self, endpoints: &'a [Endpoint], _key: Option<u64>) -> Option<&'a Endpoint> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        current.retain(|id, _| endpoints.iter().any(|e| &e.service.id == id));
+
+        let weights: Vec<f64> = endpoints.iter().map(Endpoint::effective_weight).collect();
+        let total: f64 = weights.iter().sum();
+        let mut best: Option<(&Endpoint, f64)> = None;
+        for (endpoint, effective) in endpoints.iter().zip(&weights) {
+            let weight = current.entry(endpoint.service.id.clone()).or_insert(0.0);
+            *weight += effective;
+            if best.map(|(_, w)| *weight > w).unwrap_or(true) {
+                best = Some((endpoint, *weight));
+            }
+        }
+
+        let (endpoint, _) = best?;
+        if let Some(weight) = current.get_mut(&endpoint.service.id) {
+            *weight -= total;
+        }
+        Some(endpoint)
+    }
+}
+
+/// Fewest in-flight requests per unit of weight wins; ties are broken by a
+/// random start so idle pools still spread load.
+#[derive(Debug)]
+pub struct LeastRequest;
+
+impl BalancingStrategy for LeastRequest {
+    fn pick<'a>(&self, endpoints: &'a [Endpoint], _key: Option<u64>) -> Option<&'a Endpoint> {
+        if endpoints.is_empty() {
+            return None;
+        }
+        let start = rand::thread_rng().gen_range(0..endpoints.len());
+        endpoints
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(endpoints.len())
+            .min_by(|a, b| a.load().total_cmp(&b.load()))
+    }
+}
+
+/// Sample two instances at random and take the one less loaded for its
+/// weight.
+#[derive(Debug)]
+pub struct PowerOfTwoChoices;
+
+impl BalancingStrategy for PowerOfTwoChoices {
+    fn pick<'a>(&self, endpoints: &'a [Endpoint], _key: Option<u64>) -> Option<&'a Endpoint> {
+        let mut sample = endpoints.choose_multiple(&mut rand::thread_rng(), 2);
+        let first = sample.next()?;
+        match sample.next() {
+            Some(second) if second.load() < first.load() => Some(second),
+            _ => Some(first),
+        }
+    }
+}
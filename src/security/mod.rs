@@ -2,6 +2,6 @@ mod tls;
 mod auth;
 mod rate_limit;
 
-pub use tls::TlsConfig;
+pub use tls::{upstream_connector, CertificateValidity, TlsConfig};
 pub use auth::{authenticate, authorize};
 pub use rate_limit::rate_limit;
@@ -1,18 +1,30 @@
 use crate::error::TlsConfigurationError;
 use crate::prelude::*;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use std::path::Path;
 use std::sync::Arc;
-use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig as RustlsServerConfig};
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig as RustlsServerConfig,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Validity window of the leaf certificate.
+#[derive(Debug, Clone, Copy)]
+pub struct CertificateValidity {
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+}
 
 pub struct TlsConfig {
     acceptor: TlsAcceptor,
+    validity: Option<CertificateValidity>,
 }
 
 impl TlsConfig {
     pub fn new(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self> {
         let certs = load_certs(cert_path)?;
         let key = load_private_key(key_path)?;
+        let validity = certs.first().and_then(|cert| parse_validity(&cert.0));
 
         let config = RustlsServerConfig::builder()
             .with_safe_defaults()
@@ -21,12 +33,101 @@ impl TlsConfig {
 
         Ok(Self {
             acceptor: TlsAcceptor::from(Arc::new(config)),
+            validity,
         })
     }
 
     pub fn get_acceptor(&self) -> TlsAcceptor {
         self.acceptor.clone()
     }
+
+    /// `None` if the certificate's validity could not be parsed.
+    pub fn validity(&self) -> Option<CertificateValidity> {
+        self.validity
+    }
+}
+
+/// Client TLS for HTTP/2 upstreams, trusting only the CA certificates in
+/// `ca_path`.
+pub fn upstream_connector(ca_path: impl AsRef<Path>) -> Result<TlsConnector> {
+    let certs = load_certs(ca_path.as_ref())?;
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(TlsConfigurationError::CertificatesNotFound(ca_path.as_ref().to_path_buf()).into());
+    }
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Split one DER TLV off the front of `input`, returning tag, contents and
+/// the remaining bytes.
+fn read_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+
+    let (len, rest) = if len & 0x80 == 0 {
+        (len as usize, rest)
+    } else {
+        let octets = (len & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let len = rest[..octets]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, &rest[octets..])
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+fn parse_time(tag: u8, value: &[u8]) -> Option<DateTime<Utc>> {
+    let value = std::str::from_utf8(value).ok()?;
+    let naive = match tag {
+        // UTCTime: YYMMDDHHMMSSZ, years 50-99 are 19xx
+        0x17 => {
+            let year: i32 = value.get(..2)?.parse().ok()?;
+            let century = if year >= 50 { "19" } else { "20" };
+            NaiveDateTime::parse_from_str(&format!("{}{}", century, value), "%Y%m%d%H%M%SZ").ok()?
+        }
+        // GeneralizedTime: YYYYMMDDHHMMSSZ
+        0x18 => NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%SZ").ok()?,
+        _ => return None,
+    };
+    Some(naive.and_utc())
+}
+
+/// Pull notBefore/notAfter out of an X.509 certificate.
+fn parse_validity(der: &[u8]) -> Option<CertificateValidity> {
+    let (_, certificate, _) = read_der(der)?;
+    let (_, tbs, _) = read_der(certificate)?;
+
+    // Skip the optional explicit [0] version, then serial, signature and issuer.
+    let (tag, _, mut rest) = read_der(tbs)?;
+    if tag == 0xa0 {
+        rest = read_der(rest)?.2;
+    }
+    let rest = read_der(rest)?.2;
+    let rest = read_der(rest)?.2;
+
+    let (_, validity, _) = read_der(rest)?;
+    let (before_tag, before, rest) = read_der(validity)?;
+    let (after_tag, after, _) = read_der(rest)?;
+
+    Some(CertificateValidity {
+        not_before: parse_time(before_tag, before)?,
+        not_after: parse_time(after_tag, after)?,
+    })
 }
 
 fn load_certs(path: impl AsRef<Path>) -> Result<Vec<Certificate>> {
@@ -50,3 +151,100 @@ fn load_private_key(path: impl AsRef<Path>) -> Result<PrivateKey> {
 
     Ok(PrivateKey(key))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn tlv(tag: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![tag];
+        match body.len() {
+            len if len < 0x80 => bytes.push(len as u8),
+            len if len <= 0xff => bytes.extend([0x81, len as u8]),
+            len => bytes.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    fn certificate(version: bool, issuer: &[u8], validity: &[u8]) -> Vec<u8> {
+        let mut tbs = Vec::new();
+        if version {
+            tbs.extend(tlv(0xa0, &tlv(0x02, &[2])));
+        }
+        tbs.extend(tlv(0x02, &[0x01, 0x23]));
+        tbs.extend(tlv(0x30, &tlv(0x06, &[0x2a, 0x86, 0x48])));
+        tbs.extend(tlv(0x30, issuer));
+        tbs.extend(tlv(0x30, validity));
+        tbs.extend(tlv(0x30, &[]));
+        tlv(0x30, &tlv(0x30, &tbs))
+    }
+
+    fn validity(before: (u8, &str), after: (u8, &str)) -> Vec<u8> {
+        let mut bytes = tlv(before.0, before.1.as_bytes());
+        bytes.extend(tlv(after.0, after.1.as_bytes()));
+        bytes
+    }
+
+    fn at(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn utc_time_years_split_at_1950() {
+        let der = certificate(
+            true,
+            &[],
+            &validity((0x17, "500101120000Z"), (0x17, "491231120000Z")),
+        );
+        let validity = parse_validity(&der).unwrap();
+        assert_eq!(validity.not_before, at(1950, 1, 1));
+        assert_eq!(validity.not_after, at(2049, 12, 31));
+    }
+
+    #[test]
+    fn generalized_time() {
+        let der = certificate(
+            true,
+            &[],
+            &validity((0x17, "240301120000Z"), (0x18, "20510615120000Z")),
+        );
+        let validity = parse_validity(&der).unwrap();
+        assert_eq!(validity.not_before, at(2024, 3, 1));
+        assert_eq!(validity.not_after, at(2051, 6, 15));
+    }
+
+    #[test]
+    fn v1_certificate_without_version_and_long_lengths() {
+        let issuer = tlv(0x31, &[0x55; 300]);
+        let der = certificate(
+            false,
+            &issuer,
+            &validity((0x17, "240301120000Z"), (0x17, "250301120000Z")),
+        );
+        let validity = parse_validity(&der).unwrap();
+        assert_eq!(validity.not_after, at(2025, 3, 1));
+    }
+
+    #[test]
+    fn rejects_truncated_or_malformed_input() {
+        let der = certificate(
+            true,
+            &[],
+            &validity((0x17, "240301120000Z"), (0x17, "250301120000Z")),
+        );
+        assert!(parse_validity(&der[..der.len() - 4]).is_none());
+        assert!(parse_validity(&[]).is_none());
+        assert!(read_der(&[0x30, 0x85, 1, 2, 3, 4, 5]).is_none());
+        assert!(read_der(&[0x30, 0x80]).is_none());
+
+        let bad_time = certificate(
+            true,
+            &[],
+            &validity((0x17, "24-03-01"), (0x17, "250301120000Z")),
+        );
+        assert!(parse_validity(&bad_time).is_none());
+        assert!(parse_time(0x13, b"240301120000Z").is_none());
+    }
+}
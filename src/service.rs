@@ -1,8 +1,84 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub reason: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Maintenance {
+    pub fn is_active(&self) -> bool {
+        self.expires_at.map(|expires| expires > Utc::now()).unwrap_or(true)
+    }
+}
+
+/// Where an instance or Lodestone node runs. Empty fields are unknown.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Locality {
+    #[serde(default)]
+    pub region: String,
+    #[serde(default)]
+    pub zone: String,
+    #[serde(default)]
+    pub subzone: String,
+}
+
+impl Locality {
+    pub fn is_empty(&self) -> bool {
+        self.region.is_empty() && self.zone.is_empty() && self.subzone.is_empty()
+    }
+
+    /// How far `other` is from here: 0 for the same subzone, up to 3 for
+    /// another region.
+    pub fn priority(&self, other: &Locality) -> u8 {
+        if self.region != other.region {
+            3
+        } else if self.zone != other.zone {
+            2
+        } else if self.subzone != other.subzone {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+/// Selects instances of a service by tags and metadata, e.g. the `v2`
+/// subset of `checkout` for a canary release. Empty selects every
+/// instance.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subset {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Subset {
+    pub fn matches(&self, service: &Service) -> bool {
+        self.tags.iter().all(|tag| service.tags.contains(tag))
+            && self
+                .metadata
+                .iter()
+                .all(|(key, value)| service.metadata.get(key) == Some(value))
+    }
+}
+
+/// Largest `weight` an instance may register with, so weighted layouts
+/// such as the ring-hash ring stay a sensible size.
+pub const MAX_WEIGHT: u32 = 1000;
+
+fn default_weight() -> u32 {
+    1
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Service {
     pub id: String,
@@ -12,6 +88,15 @@ pub struct Service {
     pub health_check_url: String,
     pub tags: Vec<String>,
     pub metadata: HashMap<String, String>,
+    /// Relative share of traffic under weighted strategies.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default, skip_serializing_if = "Locality::is_empty")]
+    pub locality: Locality,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<Maintenance>,
+    #[serde(default)]
+    pub draining: bool,
 }
 
 impl Service {
@@ -25,6 +110,30 @@ impl Service {
             health_check_url: format!("http://{}:{}/health", address_clone, port),
             tags: Vec::new(),
             metadata: HashMap::new(),
+            weight: default_weight(),
+            locality: Locality::default(),
+            maintenance: None,
+            draining: false,
         }
     }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.weight > MAX_WEIGHT {
+            return Err(Error::BadRequest(format!(
+                "weight {} is above the maximum of {}",
+                self.weight, MAX_WEIGHT
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.as_ref().map(Maintenance::is_active).unwrap_or(false)
+    }
+
+    /// Whether the instance may receive new traffic. Draining instances keep
+    /// their in-flight requests but get nothing new.
+    pub fn is_available(&self) -> bool {
+        !self.draining && !self.in_maintenance()
+    }
 }
@@ -1,5 +1,6 @@
 // src/store/mod.rs
 use crate::{prelude::*, service::Service};
+use serde::{de::DeserializeOwned, Serialize};
 use sled::Db;
 use std::path::Path;
 use serde_json;
@@ -65,6 +66,14 @@ impl Store {
         Ok(())
     }
 
+    /// Cheap round trip to sled to confirm the database is still usable.
+    pub fn check(&self) -> Result<()> {
+        self.db
+            .get(b"__lodestone_health__")
+            .map_err(|e| Error::Storage(e.to_string()))?;
+        Ok(())
+    }
+
     pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<Service>> {
         let mut services = Vec::new();
         
@@ -77,4 +86,102 @@ impl Store {
         
         Ok(services)
     }
+
+    /// A named tree for callers that keep their own encoding.
+    pub fn tree(&self, name: &str) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+
+    /// Store a record in a named tree, keeping non-service records out of
+    /// the default tree that `list` walks.
+    pub fn set_in<T: Serialize>(&self, tree: &str, key: &str, value: &T) -> Result<()> {
+        let tree = self.db.open_tree(tree)?;
+        let serialized = serde_json::to_vec(value)
+            .map_err(|e| Error::Storage(e.to_string()))?;
+
+        tree.insert(key.as_bytes(), serialized)
+            .map_err(|e| Error::Storage(e.to_string()))?;
+
+        tree.flush()
+            .map_err(|e| Error::Storage(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub fn get_from<T: DeserializeOwned>(&self, tree: &str, key: &str) -> Result<Option<T>> {
+        let tree = self.db.open_tree(tree)?;
+        match tree.get(key.as_bytes()).map_err(|e| Error::Storage(e.to_string()))? {
+            Some(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|e| Error::Storage(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_in<T: DeserializeOwned>(&self, tree: &str) -> Result<Vec<T>> {
+        let tree = self.db.open_tree(tree)?;
+        let mut values = Vec::new();
+
+        for item in tree.iter() {
+            let (_, value) = item.map_err(|e| Error::Storage(e.to_string()))?;
+            let value: T = serde_json::from_slice(&value)
+                .map_err(|e| Error::Storage(e.to_string()))?;
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+
+    /// Atomically replace a record with `update` applied to it. Returns
+    /// the new record, or `None` if there was none; an error from `update`
+    /// leaves the record unchanged.
+    pub fn update_in<T, F>(&self, tree: &str, key: &str, mut update: F) -> Result<Option<T>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnMut(T) -> Result<T>,
+    {
+        let tree = self.db.open_tree(tree)?;
+        let mut failed = None;
+        let updated = tree
+            .update_and_fetch(key.as_bytes(), |current| {
+                // Called again whenever another writer got in first.
+                failed = None;
+                let current = current?;
+                let updated = serde_json::from_slice(current)
+                    .map_err(|e| Error::Storage(e.to_string()))
+                    .and_then(&mut update)
+                    .and_then(|value| {
+                        serde_json::to_vec(&value).map_err(|e| Error::Storage(e.to_string()))
+                    });
+                match updated {
+                    Ok(updated) => Some(updated),
+                    Err(e) => {
+                        failed = Some(e);
+                        Some(current.to_vec())
+                    }
+                }
+            })
+            .map_err(|e| Error::Storage(e.to_string()))?;
+        if let Some(e) = failed {
+            return Err(e);
+        }
+
+        tree.flush()
+            .map_err(|e| Error::Storage(e.to_string()))?;
+
+        updated
+            .map(|data| serde_json::from_slice(&data).map_err(|e| Error::Storage(e.to_string())))
+            .transpose()
+    }
+
+    pub fn delete_from(&self, tree: &str, key: &str) -> Result<()> {
+        let tree = self.db.open_tree(tree)?;
+        tree.remove(key.as_bytes())
+            .map_err(|e| Error::Storage(e.to_string()))?;
+
+        tree.flush()
+            .map_err(|e| Error::Storage(e.to_string()))?;
+
+        Ok(())
+    }
 }
\ No newline at end of file